@@ -19,6 +19,7 @@
 extern crate test;
 
 use jsonrpc_filter::bisect_set::BisectSet;
+use jsonrpc_filter::perfect_hash::PerfectHashSet;
 use std::collections::{BTreeSet, HashSet};
 use test::{black_box, Bencher};
 
@@ -81,6 +82,10 @@ fn contains_bisect(list: &BisectSet<String>, method: &str) -> bool {
     list.contains(method)
 }
 
+fn contains_perfect_hash(list: &PerfectHashSet, method: &str) -> bool {
+    list.contains(method)
+}
+
 mod first_item {
     use super::*;
     use std::iter::FromIterator;
@@ -107,6 +112,17 @@ mod first_item {
         })
     }
 
+    #[bench]
+    fn with_perfect_hash(b: &mut Bencher) {
+        let list = PerfectHashSet::new(ALLOWED_LIST.iter().map(ToString::to_string)).unwrap();
+
+        let method = (*ALLOWED_LIST.first().unwrap()).to_string();
+
+        b.iter(|| {
+            black_box(contains_perfect_hash(&list, &method));
+        })
+    }
+
     #[bench]
     fn with_hash_set(b: &mut Bencher) {
         let list: HashSet<String> = ALLOWED_LIST.iter().map(ToString::to_string).collect();
@@ -156,6 +172,17 @@ mod second_item {
         })
     }
 
+    #[bench]
+    fn with_perfect_hash(b: &mut Bencher) {
+        let list = PerfectHashSet::new(ALLOWED_LIST.iter().map(ToString::to_string)).unwrap();
+
+        let method = (*ALLOWED_LIST.get(1).unwrap()).to_string();
+
+        b.iter(|| {
+            black_box(contains_perfect_hash(&list, &method));
+        })
+    }
+
     #[bench]
     fn with_hash_set(b: &mut Bencher) {
         let list: HashSet<String> = ALLOWED_LIST.iter().map(ToString::to_string).collect();
@@ -205,6 +232,17 @@ mod third_item {
         })
     }
 
+    #[bench]
+    fn with_perfect_hash(b: &mut Bencher) {
+        let list = PerfectHashSet::new(ALLOWED_LIST.iter().map(ToString::to_string)).unwrap();
+
+        let method = (*ALLOWED_LIST.get(2).unwrap()).to_string();
+
+        b.iter(|| {
+            black_box(contains_perfect_hash(&list, &method));
+        })
+    }
+
     #[bench]
     fn with_hash_set(b: &mut Bencher) {
         let list: HashSet<String> = ALLOWED_LIST.iter().map(ToString::to_string).collect();
@@ -253,6 +291,17 @@ mod q1_item {
         })
     }
 
+    #[bench]
+    fn with_perfect_hash(b: &mut Bencher) {
+        let list = PerfectHashSet::new(ALLOWED_LIST.iter().map(ToString::to_string)).unwrap();
+
+        let method = (*ALLOWED_LIST.get(ALLOWED_LIST.len() / 4).unwrap()).to_string();
+
+        b.iter(|| {
+            black_box(contains_perfect_hash(&list, &method));
+        })
+    }
+
     #[bench]
     fn with_hash_set(b: &mut Bencher) {
         let list: HashSet<String> = ALLOWED_LIST.iter().map(ToString::to_string).collect();
@@ -299,6 +348,17 @@ mod middle_item {
         })
     }
 
+    #[bench]
+    fn with_perfect_hash(b: &mut Bencher) {
+        let list = PerfectHashSet::new(ALLOWED_LIST.iter().map(ToString::to_string)).unwrap();
+
+        let method = (*ALLOWED_LIST.get(ALLOWED_LIST.len() / 2).unwrap()).to_string();
+
+        b.iter(|| {
+            black_box(contains_perfect_hash(&list, &method));
+        })
+    }
+
     #[bench]
     fn with_hash_set(b: &mut Bencher) {
         let list: HashSet<String> = ALLOWED_LIST.iter().map(ToString::to_string).collect();
@@ -345,6 +405,17 @@ mod q3_item {
         })
     }
 
+    #[bench]
+    fn with_perfect_hash(b: &mut Bencher) {
+        let list = PerfectHashSet::new(ALLOWED_LIST.iter().map(ToString::to_string)).unwrap();
+
+        let method = (*ALLOWED_LIST.get(3 * ALLOWED_LIST.len() / 4).unwrap()).to_string();
+
+        b.iter(|| {
+            black_box(contains_perfect_hash(&list, &method));
+        })
+    }
+
     #[bench]
     fn with_hash_set(b: &mut Bencher) {
         let vec: HashSet<String> = ALLOWED_LIST.iter().map(ToString::to_string).collect();
@@ -391,6 +462,17 @@ mod last_item {
         })
     }
 
+    #[bench]
+    fn with_perfect_hash(b: &mut Bencher) {
+        let list = PerfectHashSet::new(ALLOWED_LIST.iter().map(ToString::to_string)).unwrap();
+
+        let method = (*ALLOWED_LIST.last().unwrap()).to_string();
+
+        b.iter(|| {
+            black_box(contains_perfect_hash(&list, &method));
+        })
+    }
+
     #[bench]
     fn with_hash_set(b: &mut Bencher) {
         let vec: HashSet<String> = ALLOWED_LIST.iter().map(ToString::to_string).collect();
@@ -437,6 +519,17 @@ mod non_exist {
         })
     }
 
+    #[bench]
+    fn with_perfect_hash(b: &mut Bencher) {
+        let list = PerfectHashSet::new(ALLOWED_LIST.iter().map(ToString::to_string)).unwrap();
+
+        let method = "chain_getNonExist".to_string();
+
+        b.iter(|| {
+            black_box(contains_perfect_hash(&list, &method));
+        })
+    }
+
     #[bench]
     fn with_hash_set(b: &mut Bencher) {
         let vec: HashSet<String> = ALLOWED_LIST.iter().map(ToString::to_string).collect();
@@ -457,3 +550,141 @@ mod non_exist {
         })
     }
 }
+
+mod large_list {
+    use super::*;
+    use std::iter::FromIterator;
+
+    const SIZE: usize = 10_000;
+
+    fn methods() -> Vec<String> {
+        (0..SIZE).map(|index| format!("module{}_getItem{}", index % 100, index)).collect()
+    }
+
+    /// Every fourth lookup hits.
+    fn targets() -> Vec<String> {
+        (0..100)
+            .map(|index| {
+                if index % 4 == 0 {
+                    let item = index * 97 % SIZE;
+                    format!("module{}_getItem{}", item % 100, item)
+                } else {
+                    format!("module{}_getItem{}", index, SIZE + index)
+                }
+            })
+            .collect()
+    }
+
+    #[bench]
+    fn with_vec_iter(b: &mut Bencher) {
+        let list = methods();
+        let targets = targets();
+
+        b.iter(|| {
+            for method in &targets {
+                black_box(contains_linear(&list, method));
+            }
+        })
+    }
+
+    #[bench]
+    fn with_vec_bisect(b: &mut Bencher) {
+        let list = BisectSet::from_iter(methods());
+        let targets = targets();
+
+        b.iter(|| {
+            for method in &targets {
+                black_box(contains_bisect(&list, method));
+            }
+        })
+    }
+
+    #[bench]
+    fn with_perfect_hash(b: &mut Bencher) {
+        let list = PerfectHashSet::new(methods()).unwrap();
+        let targets = targets();
+
+        b.iter(|| {
+            for method in &targets {
+                black_box(contains_perfect_hash(&list, method));
+            }
+        })
+    }
+
+    #[bench]
+    fn with_hash_set(b: &mut Bencher) {
+        let list: HashSet<String> = methods().into_iter().collect();
+        let targets = targets();
+
+        b.iter(|| {
+            for method in &targets {
+                black_box(contains_hash_set(&list, method));
+            }
+        })
+    }
+
+    #[bench]
+    fn with_btree_set(b: &mut Bencher) {
+        let list: BTreeSet<String> = methods().into_iter().collect();
+        let targets = targets();
+
+        b.iter(|| {
+            for method in &targets {
+                black_box(contains_btree_set(&list, method));
+            }
+        })
+    }
+
+    #[bench]
+    fn build_perfect_hash(b: &mut Bencher) {
+        let methods = methods();
+
+        b.iter(|| {
+            black_box(PerfectHashSet::new(methods.iter().cloned()));
+        })
+    }
+}
+
+mod method_extraction {
+    use super::*;
+    use jsonrpc_filter::envelope;
+    use serde_json::Value;
+
+    /// A `mempool_sendSignedTransaction` with a transaction of `size` bytes, with the method after the params.
+    fn send_signed_transaction(size: usize) -> Vec<u8> {
+        format!(
+            r#"{{"jsonrpc":"2.0","id":1,"params":["0x{}"],"method":"mempool_sendSignedTransaction"}}"#,
+            "f8".repeat(size)
+        )
+        .into_bytes()
+    }
+
+    fn full_parse(buffer: &[u8]) -> String {
+        let request: Value = serde_json::from_slice(buffer).unwrap();
+        request["method"].as_str().unwrap().to_string()
+    }
+
+    #[bench]
+    fn full_parse_1kb(b: &mut Bencher) {
+        let buffer = send_signed_transaction(1 << 10);
+        b.iter(|| black_box(full_parse(&buffer)))
+    }
+
+    #[bench]
+    fn envelope_1kb(b: &mut Bencher) {
+        let buffer = send_signed_transaction(1 << 10);
+        b.iter(|| black_box(envelope::method(&buffer).unwrap().len()))
+    }
+
+    #[bench]
+    fn full_parse_1mb(b: &mut Bencher) {
+        let buffer = send_signed_transaction(1 << 20);
+        b.iter(|| black_box(full_parse(&buffer)))
+    }
+
+    #[bench]
+    fn envelope_1mb(b: &mut Bencher) {
+        let buffer = send_signed_transaction(1 << 20);
+        b.iter(|| black_box(envelope::method(&buffer).unwrap().len()))
+    }
+}
@@ -0,0 +1,330 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::metrics::Outcome;
+use crossbeam_channel::{bounded, Receiver, Sender};
+use hyper::StatusCode;
+use log::warn;
+use serde_json::json;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// What happened to a request, written as a line of the access log.
+pub struct Access {
+    timestamp: SystemTime,
+    started: Instant,
+    pub seq: u64,
+    pub peer: IpAddr,
+    pub request_id: String,
+    /// The identity given by the authenticating proxy in front of the filter.
+    pub identity: Option<String>,
+    pub method: Option<String>,
+    pub outcome: Option<Outcome>,
+    /// The rule that decided the outcome.
+    pub rule: Option<String>,
+    /// Why a rule in the monitor mode would block the request.
+    pub would_block: Option<String>,
+    pub upstream_status: Option<StatusCode>,
+    pub request_bytes: usize,
+    pub response_bytes: usize,
+}
+
+impl Access {
+    pub fn new(seq: u64, peer: IpAddr, request_id: String) -> Self {
+        Access {
+            timestamp: SystemTime::now(),
+            started: Instant::now(),
+            seq,
+            peer,
+            request_id,
+            identity: None,
+            method: None,
+            outcome: None,
+            rule: None,
+            would_block: None,
+            upstream_status: None,
+            request_bytes: 0,
+            response_bytes: 0,
+        }
+    }
+
+    /// The time since the request arrived.
+    pub fn latency(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    fn to_line(&self) -> String {
+        let latency = self.latency();
+        json!({
+            "timestamp": format_timestamp(self.timestamp),
+            "seq": self.seq,
+            "peer": self.peer.to_string(),
+            "request_id": self.request_id,
+            "identity": self.identity,
+            "method": self.method,
+            "decision": self.outcome.map(Outcome::label),
+            "rule": self.rule,
+            "would_block": self.would_block,
+            "upstream_status": self.upstream_status.map(|status| status.as_u16()),
+            "latency_ms": latency.as_secs_f64() * 1000.0,
+            "request_bytes": self.request_bytes,
+            "response_bytes": self.response_bytes,
+        })
+        .to_string()
+    }
+}
+
+/// When the log file is moved aside and a new one is started.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rotation {
+    pub max_size: Option<u64>,
+    pub max_age: Option<Duration>,
+}
+
+pub enum Output {
+    Stdout,
+    File {
+        path: PathBuf,
+        rotation: Rotation,
+    },
+}
+
+/// The number of entries a writer can fall behind before the new ones are dropped.
+const QUEUE_SIZE: usize = 10_000;
+
+/// Sends the entries to a writer in the background. An entry is dropped instead of waiting when the writer is
+/// `QUEUE_SIZE` entries behind, e.g. because the disk is slow.
+///
+/// The filters share the sender, which sends without a lock.
+pub struct Queue<T> {
+    name: &'static str,
+    sender: Sender<T>,
+    dropped: AtomicU64,
+}
+
+impl<T> Queue<T> {
+    pub fn new(name: &'static str) -> (Self, Receiver<T>) {
+        let (sender, receiver) = bounded(QUEUE_SIZE);
+        let queue = Queue {
+            name,
+            sender,
+            dropped: AtomicU64::new(0),
+        };
+        (queue, receiver)
+    }
+
+    pub fn send(&self, entry: T) {
+        let sent = self.sender.try_send(entry);
+        // The receiver lives as long as the process, so the queue is full.
+        if sent.is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            // Logs at 1, 2, 4, 8, ... not to flood the log while the writer is behind.
+            if dropped.is_power_of_two() {
+                warn!("The {} is behind, and {} entries have been dropped", self.name.replace('-', " "), dropped);
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The number of entries dropped because the writer was behind.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Writes the lines sent to the returned queue on a dedicated thread, so that requests never wait for the disk.
+pub fn spawn_writer(name: &'static str, output: Output) -> io::Result<Queue<String>> {
+    let mut writer: Box<dyn Write + Send> = match output {
+        Output::Stdout => Box::new(io::stdout()),
+        Output::File {
+            path,
+            rotation,
+        } => Box::new(RotatingFile::open(path, rotation)?),
+    };
+    let (queue, receiver) = Queue::new(name);
+    let description = name.replace('-', " ");
+    thread::Builder::new().name(name.to_string()).spawn(move || {
+        for line in receiver {
+            // A line is written at once, so that the rotation doesn't split it.
+            let line = format!("{}\n", line);
+            if let Err(err) = writer.write_all(line.as_bytes()).and_then(|()| writer.flush()) {
+                warn!("Cannot write the {}: {}", description, err);
+            }
+        }
+    })?;
+    Ok(queue)
+}
+
+pub struct AccessLog {
+    queue: Queue<String>,
+}
+
+impl AccessLog {
+    pub fn new(output: Output) -> io::Result<Self> {
+        Ok(AccessLog {
+            queue: spawn_writer("access-log", output)?,
+        })
+    }
+
+    pub fn write(&self, access: &Access) {
+        self.queue.send(access.to_line());
+    }
+
+    pub fn queue(&self) -> &Queue<String> {
+        &self.queue
+    }
+}
+
+/// A file that is renamed with the current unix time appended and started again by the rotation.
+pub(crate) struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    opened_at: Instant,
+}
+
+impl RotatingFile {
+    pub(crate) fn open(path: PathBuf, rotation: Rotation) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            rotation,
+            file,
+            size,
+            opened_at: Instant::now(),
+        })
+    }
+
+    fn should_rotate(&self) -> bool {
+        if self.size == 0 {
+            return false
+        }
+        let too_big = match self.rotation.max_size {
+            Some(max_size) => self.size >= max_size,
+            None => false,
+        };
+        let too_old = match self.rotation.max_age {
+            Some(max_age) => self.opened_at.elapsed() >= max_age,
+            None => false,
+        };
+        too_big || too_old
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0);
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{}", seconds));
+        let mut rotated = PathBuf::from(rotated);
+        let mut suffix = 1;
+        while rotated.exists() {
+            let mut renamed = self.path.clone().into_os_string();
+            renamed.push(format!(".{}.{}", seconds, suffix));
+            rotated = PathBuf::from(renamed);
+            suffix += 1;
+        }
+        fs::rename(&self.path, rotated)?;
+        *self = RotatingFile::open(self.path.clone(), self.rotation)?;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_rotate() {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Formats the time in RFC 3339 with milliseconds in UTC.
+pub fn format_timestamp(time: SystemTime) -> String {
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = elapsed.as_secs();
+    let days = (seconds / 86_400) as i64;
+    let seconds_of_day = seconds % 86_400;
+
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 {
+        mp + 3
+    } else {
+        mp - 9
+    };
+    let year = year_of_era
+        + era * 400
+        + if month <= 2 {
+            1
+        } else {
+            0
+        };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+        elapsed.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamp() {
+        assert_eq!("1970-01-01T00:00:00.000Z", format_timestamp(UNIX_EPOCH));
+        assert_eq!("2020-02-29T12:34:56.789Z", format_timestamp(UNIX_EPOCH + Duration::from_millis(1_582_979_696_789)));
+    }
+
+    #[test]
+    fn drop_the_entries_when_the_writer_is_behind() {
+        let (queue, receiver) = Queue::new("access-log");
+        for line in 0..QUEUE_SIZE + 2 {
+            queue.send(line);
+        }
+        assert_eq!(2, queue.dropped());
+        assert_eq!(Ok(0), receiver.try_recv());
+        queue.send(QUEUE_SIZE + 2);
+        assert_eq!(2, queue.dropped());
+    }
+}
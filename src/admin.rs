@@ -0,0 +1,386 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::Error;
+use crate::access_log::{format_timestamp, spawn_writer, Output, Queue};
+use crate::allowed_list::MethodOptions;
+use crate::config::Config;
+use crate::filter::{collect_body, DynFuture};
+use crate::metrics::render_dropped_entries;
+use futures::future;
+use hyper::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use hyper::server::conn::AddrStream;
+use hyper::service::Service;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_json::{json, Value};
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+/// Serves the endpoints for the operators on a separate port.
+pub struct Admin {
+    config: Arc<Config>,
+    api: Arc<PolicyApi>,
+    peer: IpAddr,
+}
+
+impl Service<Request<Body>> for Admin {
+    type Response = Response<Body>;
+    type Error = Error;
+    type Future = DynFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let config = Arc::clone(&self.config);
+        let api = Arc::clone(&self.api);
+        let peer = self.peer;
+        Box::pin(async move {
+            let path = req.uri().path();
+            if path == "/policy" || path.starts_with("/policy/") {
+                return api.handle(&config, peer, req).await
+            }
+            let response = match (req.method(), path) {
+                (&Method::GET, "/metrics") => {
+                    let mut metrics = config.metrics.render(&config.cache);
+                    let dropped = config
+                        .access_log
+                        .iter()
+                        .map(|access_log| dropped_entries(access_log.queue()))
+                        .chain(config.trace_exporter.iter().map(|exporter| dropped_entries(exporter.queue())))
+                        .chain(config.recorder.iter().map(|recorder| dropped_entries(recorder.queue())))
+                        .chain(api.audit_log.iter().map(|audit_log| dropped_entries(&audit_log.queue)));
+                    render_dropped_entries(&mut metrics, dropped);
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .header(CONTENT_TYPE, HeaderValue::from_static("text/plain; version=0.0.4"))
+                        .body(Body::from(metrics))
+                }
+                _ => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()),
+            };
+            Ok(response?)
+        })
+    }
+}
+
+fn dropped_entries<T>(queue: &Queue<T>) -> (&'static str, u64) {
+    (queue.name(), queue.dropped())
+}
+
+/// Records every request to the policy API as a JSON line.
+pub struct AuditLog {
+    queue: Queue<String>,
+}
+
+impl AuditLog {
+    pub fn new(output: Output) -> io::Result<Self> {
+        Ok(AuditLog {
+            queue: spawn_writer("audit-log", output)?,
+        })
+    }
+
+    fn write(&self, entry: &Value) {
+        self.queue.send(entry.to_string());
+    }
+}
+
+/// Inspects and changes the policy at runtime.
+///
+/// Every endpoint requires `Authorization: Bearer <token>`, and the API is disabled when no token is configured.
+pub struct PolicyApi {
+    token: Option<String>,
+    audit_log: Option<AuditLog>,
+}
+
+impl PolicyApi {
+    pub fn new(token: Option<String>, audit_log: Option<AuditLog>) -> Self {
+        PolicyApi {
+            token,
+            audit_log,
+        }
+    }
+
+    async fn handle(&self, config: &Config, peer: IpAddr, req: Request<Body>) -> Result<Response<Body>, Error> {
+        let policy = &config.policy;
+        let (parts, body) = req.into_parts();
+        let save = match parts.uri.query() {
+            Some(query) => query.split('&').any(|pair| pair == "save" || pair == "save=true"),
+            None => false,
+        };
+        let mut saved = false;
+        let mut body_text = None;
+        let result = match self.authorize(&parts.headers) {
+            Ok(()) => {
+                let body = collect_body(body).await?;
+                let text = String::from_utf8_lossy(&body).into_owned();
+                let result = apply(config, &parts.method, parts.uri.path(), &text);
+                body_text = Some(text);
+                if parts.method != Method::GET {
+                    config.metrics.add_methods(policy.allowed_list().methods());
+                }
+                match result {
+                    Ok(()) if save && parts.method != Method::GET => match policy.save() {
+                        Ok(()) => {
+                            saved = true;
+                            Ok(())
+                        }
+                        Err(err) => {
+                            Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Cannot save the allowed list: {}", err)))
+                        }
+                    },
+                    result => result,
+                }
+            }
+            Err(err) => Err(err),
+        };
+        let (status, response) = match &result {
+            Ok(()) => (StatusCode::OK, snapshot(config)),
+            Err((status, reason)) => (*status, json!({ "error": reason })),
+        };
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.write(&json!({
+                "timestamp": format_timestamp(SystemTime::now()),
+                "peer": peer.to_string(),
+                "method": parts.method.as_str(),
+                "path": parts.uri.path(),
+                "body": body_text,
+                "status": status.as_u16(),
+                "saved": saved,
+                "error": result.err().map(|(_, reason)| reason),
+            }));
+        }
+        Ok(Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+            .body(Body::from(response.to_string()))?)
+    }
+
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+        let token = match &self.token {
+            Some(token) => token,
+            None => {
+                return Err((
+                    StatusCode::FORBIDDEN,
+                    "The policy API is disabled. Set --admin-token-file to enable it".to_string(),
+                ))
+            }
+        };
+        let authorization = headers.get(AUTHORIZATION).and_then(|value| value.to_str().ok()).unwrap_or_default();
+        let given = authorization.splitn(2, ' ').collect::<Vec<_>>();
+        match given[..] {
+            ["Bearer", given] if constant_time_eq(given.as_bytes(), token.as_bytes()) => Ok(()),
+            _ => Err((StatusCode::UNAUTHORIZED, "Invalid token".to_string())),
+        }
+    }
+}
+
+fn apply(config: &Config, method: &Method, path: &str, body: &str) -> Result<(), (StatusCode, String)> {
+    let policy = &config.policy;
+    let segments = path.trim_matches('/').split('/').map(percent_decode).collect::<Result<Vec<_>, _>>()?;
+    let segments = segments.iter().map(String::as_str).collect::<Vec<_>>();
+    match (method, &segments[..]) {
+        (&Method::GET, ["policy"]) => Ok(()),
+        (&Method::PUT, ["policy", "methods", name]) if !name.is_empty() => {
+            let options = MethodOptions::parse(body).map_err(|reason| (StatusCode::BAD_REQUEST, reason))?;
+            if policy.allow(name, options) {
+                Ok(())
+            } else {
+                Err((StatusCode::CONFLICT, format!("{} is denied by the allowed list", name)))
+            }
+        }
+        (&Method::DELETE, ["policy", "methods", name]) => {
+            if policy.deny(name) {
+                Ok(())
+            } else {
+                Err((StatusCode::NOT_FOUND, format!("{} is not allowed", name)))
+            }
+        }
+        (&Method::PUT, ["policy", "banned-peers", peer]) => {
+            policy.ban(parse_peer(peer)?);
+            Ok(())
+        }
+        (&Method::DELETE, ["policy", "banned-peers", peer]) => {
+            if policy.unban(parse_peer(peer)?) {
+                Ok(())
+            } else {
+                Err((StatusCode::NOT_FOUND, format!("{} is not banned", peer)))
+            }
+        }
+        (&Method::PUT, ["policy", "banned-identities", identity]) if !identity.is_empty() => {
+            policy.ban_identity(identity);
+            Ok(())
+        }
+        (&Method::DELETE, ["policy", "banned-identities", identity]) => {
+            if policy.unban_identity(identity) {
+                Ok(())
+            } else {
+                Err((StatusCode::NOT_FOUND, format!("{} is not banned", identity)))
+            }
+        }
+        (&Method::POST, ["policy", "reload"]) => {
+            // Every policy is reloaded, and the one that fails keeps its rules.
+            let failures = config
+                .policies
+                .iter()
+                .enumerate()
+                .filter_map(|(index, policy)| policy.reload().err().map(|err| format!("policy {}: {}", index, err)))
+                .collect::<Vec<_>>();
+            if failures.is_empty() {
+                Ok(())
+            } else {
+                Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("Cannot reload {}. The other policies are reloaded", failures.join(", ")),
+                ))
+            }
+        }
+        _ => Err((StatusCode::NOT_FOUND, format!("{} {} is not an endpoint", method, path))),
+    }
+}
+
+/// Decodes the `%XX` escapes of a path segment, so that an identity can have `/` or `%`.
+fn percent_decode(segment: &str) -> Result<String, (StatusCode, String)> {
+    let invalid = || (StatusCode::BAD_REQUEST, format!("{} has an invalid escape", segment));
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let digit = |index: usize| tail.get(index).and_then(|digit| char::from(*digit).to_digit(16));
+            match (digit(0), digit(1)) {
+                (Some(high), Some(low)) => bytes.push((high << 4 | low) as u8),
+                _ => return Err(invalid()),
+            }
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+fn parse_peer(peer: &str) -> Result<IpAddr, (StatusCode, String)> {
+    peer.parse().map_err(|_| (StatusCode::BAD_REQUEST, format!("{} is not an IP address", peer)))
+}
+
+/// The current policy, as returned by every successful request.
+fn snapshot(config: &Config) -> Value {
+    let policy = &config.policy;
+    let allowed_list = policy.allowed_list();
+    let methods = allowed_list
+        .methods()
+        .map(|method| {
+            let options = allowed_list.options(method).map(ToString::to_string).unwrap_or_default();
+            (method.to_string(), Value::String(options))
+        })
+        .collect::<serde_json::Map<_, _>>();
+    let banned_peers = policy.banned_peers().iter().map(ToString::to_string).collect::<Vec<_>>();
+    let rate_limit = config.rate_limiter.as_ref().map(|rate_limiter| {
+        let remaining = rate_limiter
+            .remaining()
+            .into_iter()
+            .map(|(peer, tokens)| (peer.to_string(), json!(tokens)))
+            .collect::<serde_json::Map<_, _>>();
+        json!({
+            "per_second": rate_limiter.per_second(),
+            "remaining": remaining,
+        })
+    });
+    json!({
+        "methods": methods,
+        "banned_peers": banned_peers,
+        "banned_identities": policy.banned_identities(),
+        "rate_limit": rate_limit,
+    })
+}
+
+/// Compares the tokens without leaking the length of the common prefix through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub struct AdminMaker {
+    config: Arc<Config>,
+    api: Arc<PolicyApi>,
+}
+
+impl AdminMaker {
+    pub fn new(config: Arc<Config>, api: PolicyApi) -> Self {
+        Self {
+            config,
+            api: Arc::new(api),
+        }
+    }
+}
+
+impl Service<&AddrStream> for AdminMaker {
+    type Response = Admin;
+    type Error = Error;
+    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, stream: &AddrStream) -> Self::Future {
+        future::ok(Admin {
+            config: Arc::clone(&self.config),
+            api: Arc::clone(&self.api),
+            peer: stream.remote_addr().ip(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api() -> PolicyApi {
+        PolicyApi::new(Some("secret".to_string()), None)
+    }
+
+    fn headers(authorization: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static(authorization));
+        headers
+    }
+
+    #[test]
+    fn authorize_with_bearer_token() {
+        assert_eq!(Ok(()), api().authorize(&headers("Bearer secret")));
+        assert_eq!(StatusCode::UNAUTHORIZED, api().authorize(&headers("Bearer secret2")).unwrap_err().0);
+        assert_eq!(StatusCode::UNAUTHORIZED, api().authorize(&headers("Basic secret")).unwrap_err().0);
+        assert_eq!(StatusCode::UNAUTHORIZED, api().authorize(&HeaderMap::new()).unwrap_err().0);
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            PolicyApi::new(None, None).authorize(&headers("Bearer secret")).unwrap_err().0
+        );
+    }
+
+    #[test]
+    fn decode_the_path_segments() {
+        assert_eq!(Ok("key/1%".to_string()), percent_decode("key%2F1%25"));
+        assert_eq!(Ok("café".to_string()), percent_decode("caf%C3%A9"));
+        assert_eq!(Ok("ping".to_string()), percent_decode("ping"));
+        assert_eq!(StatusCode::BAD_REQUEST, percent_decode("key%2").unwrap_err().0);
+        assert_eq!(StatusCode::BAD_REQUEST, percent_decode("key%+1").unwrap_err().0);
+        assert_eq!(StatusCode::BAD_REQUEST, percent_decode("%ff").unwrap_err().0);
+    }
+}
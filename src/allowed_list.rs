@@ -0,0 +1,491 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::Error;
+use crate::cache::Lifetime;
+use crate::method_set::{MethodSet, MethodSetKind};
+use crate::redact::{JsonPath, ResponseRule};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MethodOptions {
+    /// How long a successful response of the method can be served from the cache.
+    pub cache: Option<Lifetime>,
+    /// Whether identical requests in flight are sent to the upstream only once.
+    pub coalesce: bool,
+    /// The rules applied in order to the responses of the method.
+    pub response: Vec<ResponseRule>,
+}
+
+impl MethodOptions {
+    /// Parses whitespace separated options, e.g. `cache=tip coalesce`.
+    pub fn parse(options: &str) -> Result<Self, String> {
+        let mut method_options = MethodOptions::default();
+        for token in options.split_whitespace() {
+            parse_option(&mut method_options, token)?;
+        }
+        Ok(method_options)
+    }
+}
+
+impl Display for MethodOptions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let mut tokens = Vec::new();
+        match self.cache {
+            Some(Lifetime::Ttl(ttl)) => tokens.push(format!("cache={}", ttl.as_secs())),
+            Some(Lifetime::UntilNextBlock) => tokens.push("cache=tip".to_string()),
+            None => {}
+        }
+        if self.coalesce {
+            tokens.push("coalesce".to_string());
+        }
+        tokens.extend(self.response.iter().map(ToString::to_string));
+        write!(f, "{}", tokens.join(" "))
+    }
+}
+
+/// The allowed RPCs and their options.
+///
+/// Each line of the file is an RPC name followed by optional whitespace separated options, e.g.
+/// `chain_getBlockByHash cache=3600`. A line starting with `!` denies a method, or the methods with a prefix if it
+/// ends with `*`, e.g. `!personal_*`, whether or not they are listed. `@method-set perfect-hash` looks the methods up
+/// with a perfect hash instead of a binary search. Blank lines and lines starting with `#` are ignored.
+#[derive(Clone)]
+pub struct AllowedList {
+    kind: MethodSetKind,
+    methods: Arc<dyn MethodSet>,
+    options: HashMap<String, MethodOptions>,
+    /// The `!` lines, in the order of the file.
+    denied: Vec<Deny>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Deny {
+    pattern: String,
+    /// Whether the rule only reports the methods that it would block.
+    monitor: bool,
+}
+
+/// Why the allowed list doesn't allow a method.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Denial {
+    pub reason: String,
+    /// Whether the denial is by a rule in the monitor mode, which doesn't block the method.
+    pub monitored: bool,
+}
+
+impl AllowedList {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::parse(BufReader::new(File::open(path)?))
+    }
+
+    pub fn parse<R: BufRead>(reader: R) -> Result<Self, Error> {
+        let mut kind = MethodSetKind::Bisect;
+        let mut methods = Vec::new();
+        let mut options = HashMap::new();
+        let mut denied = Vec::new();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            let invalid = |reason: String| Error::InvalidAllowedList {
+                line: index + 1,
+                reason,
+            };
+            match Entry::parse(&line) {
+                Entry::Empty | Entry::Comment => {}
+                Entry::Directive(name, rest) => kind = parse_directive(name, rest).map_err(invalid)?,
+                Entry::Deny(pattern, rest) => {
+                    let monitor = match rest.trim() {
+                        "" => false,
+                        "monitor" => true,
+                        _ => return Err(invalid(format!("A deny of {} can only have the monitor option", pattern))),
+                    };
+                    denied.push(Deny {
+                        pattern: pattern.to_string(),
+                        monitor,
+                    });
+                }
+                Entry::Allow(method, rest) => {
+                    let method_options = MethodOptions::parse(rest).map_err(invalid)?;
+                    if method_options != MethodOptions::default() {
+                        options.insert(method.to_string(), method_options);
+                    }
+                    methods.push(method.to_string());
+                }
+            }
+        }
+        Ok(AllowedList {
+            kind,
+            methods: kind.build(methods),
+            options,
+            denied,
+        })
+    }
+
+    /// A denied method is not allowed even if it's in the list.
+    pub fn contains(&self, method: &str) -> bool {
+        !self.is_denied(method) && self.methods.contains(method)
+    }
+
+    /// Whether a `!` line that is not in the monitor mode denies the method.
+    pub fn is_denied(&self, method: &str) -> bool {
+        self.deny(method, false).is_some()
+    }
+
+    /// Returns why the method is not allowed. The rules in the monitor mode are asked last, so their denial means that
+    /// the method is allowed otherwise.
+    pub fn denial(&self, method: &str) -> Option<Denial> {
+        let denied = |deny: &Deny, monitored| Denial {
+            reason: format!("{} is denied by !{}", method, deny.pattern),
+            monitored,
+        };
+        if let Some(deny) = self.deny(method, false) {
+            return Some(denied(deny, false))
+        }
+        if !self.methods.contains(method) {
+            return Some(Denial {
+                reason: format!("{} is not in the allowed list", method),
+                monitored: false,
+            })
+        }
+        self.deny(method, true).map(|deny| denied(deny, true))
+    }
+
+    fn deny(&self, method: &str, monitor: bool) -> Option<&Deny> {
+        self.denied.iter().find(|deny| deny.monitor == monitor && denies(&deny.pattern, method))
+    }
+
+    /// The methods in sorted order.
+    pub fn methods(&self) -> impl Iterator<Item = &str> {
+        self.methods.methods()
+    }
+
+    pub fn kind(&self) -> MethodSetKind {
+        self.kind
+    }
+
+    pub fn options(&self, method: &str) -> Option<&MethodOptions> {
+        self.options.get(method)
+    }
+
+    /// Returns a copy of the list that allows `method` with `options`, replacing its previous options.
+    pub fn with_method(&self, method: &str, options: MethodOptions) -> Self {
+        let mut list = self.without_method(method);
+        list.methods = self.kind.build(list.methods().chain(std::iter::once(method)).map(ToString::to_string));
+        if options != MethodOptions::default() {
+            list.options.insert(method.to_string(), options);
+        }
+        list
+    }
+
+    pub fn without_method(&self, method: &str) -> Self {
+        let mut options = self.options.clone();
+        options.remove(method);
+        AllowedList {
+            kind: self.kind,
+            methods: self.kind.build(self.methods().filter(|name| *name != method).map(ToString::to_string)),
+            options,
+            denied: self.denied.clone(),
+        }
+    }
+
+    /// Writes the list over the contents of its file. The blank lines, the comments and the `!` lines are kept in
+    /// place, the lines of the removed methods are dropped, and the added methods are appended in sorted order.
+    pub fn update(&self, contents: &str) -> String {
+        let line = |method: &str| match self.options(method) {
+            Some(options) => format!("{} {}\n", method, options),
+            None => format!("{}\n", method),
+        };
+        let mut written = HashSet::new();
+        let mut updated = String::new();
+        for original in contents.lines() {
+            match Entry::parse(original) {
+                Entry::Allow(method, rest) => {
+                    if !self.methods.contains(method) || !written.insert(method) {
+                        continue
+                    }
+                    if MethodOptions::parse(rest) == Ok(self.options(method).cloned().unwrap_or_default()) {
+                        updated.push_str(original);
+                        updated.push('\n');
+                    } else {
+                        updated.push_str(&line(method));
+                    }
+                }
+                _ => {
+                    updated.push_str(original);
+                    updated.push('\n');
+                }
+            }
+        }
+        for method in self.methods().filter(|method| !written.contains(method)) {
+            updated.push_str(&line(method));
+        }
+        updated
+    }
+
+    /// Whether any cached response has to be invalidated when a new block arrives.
+    pub fn depends_on_tip(&self) -> bool {
+        self.options.values().any(|options| options.cache == Some(Lifetime::UntilNextBlock))
+    }
+}
+
+/// Writes the list in the format of the file.
+impl Display for AllowedList {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        if self.kind != MethodSetKind::Bisect {
+            writeln!(f, "@method-set {}", self.kind)?;
+        }
+        for deny in &self.denied {
+            if deny.monitor {
+                writeln!(f, "!{} monitor", deny.pattern)?;
+            } else {
+                writeln!(f, "!{}", deny.pattern)?;
+            }
+        }
+        for method in self.methods() {
+            match self.options(method) {
+                Some(options) => writeln!(f, "{} {}", method, options)?,
+                None => writeln!(f, "{}", method)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A line of the allowed list.
+pub(crate) enum Entry<'a> {
+    Empty,
+    Comment,
+    /// The method and the rest of the line.
+    Allow(&'a str, &'a str),
+    /// The pattern and the rest of the line.
+    Deny(&'a str, &'a str),
+    /// The name and the rest of the line.
+    Directive(&'a str, &'a str),
+}
+
+impl<'a> Entry<'a> {
+    pub(crate) fn parse(line: &'a str) -> Self {
+        let line = line.trim_start();
+        let (name, rest) = line.split_at(line.find(char::is_whitespace).unwrap_or(line.len()));
+        let mut chars = name.chars();
+        match chars.next() {
+            None => Entry::Empty,
+            Some('#') => Entry::Comment,
+            Some('!') => Entry::Deny(chars.as_str(), rest),
+            Some('@') => Entry::Directive(chars.as_str(), rest),
+            Some(_) => Entry::Allow(name, rest),
+        }
+    }
+}
+
+/// Returns the method set of a `@` line, which is the only directive.
+pub(crate) fn parse_directive(name: &str, value: &str) -> Result<MethodSetKind, String> {
+    match name {
+        "method-set" => value.trim().parse(),
+        _ => Err(format!("Unknown directive @{}", name)),
+    }
+}
+
+/// The prefix of a pattern that ends with `*`.
+pub(crate) fn prefix(pattern: &str) -> Option<&str> {
+    match pattern.char_indices().last() {
+        Some((index, '*')) => Some(&pattern[..index]),
+        _ => None,
+    }
+}
+
+/// Whether the pattern of a `!` line denies the method.
+pub(crate) fn denies(pattern: &str, method: &str) -> bool {
+    match prefix(pattern) {
+        Some(prefix) => method.starts_with(prefix),
+        None => pattern == method,
+    }
+}
+
+fn parse_option(options: &mut MethodOptions, token: &str) -> Result<(), String> {
+    let mut pair = token.splitn(2, '=');
+    let key = pair.next().unwrap_or_default();
+    let value = pair.next();
+    match (key, value) {
+        ("coalesce", None) => {
+            options.coalesce = true;
+            Ok(())
+        }
+        ("cache", Some("tip")) => {
+            options.cache = Some(Lifetime::UntilNextBlock);
+            Ok(())
+        }
+        ("cache", Some(seconds)) => {
+            let seconds = seconds.parse().map_err(|_| format!("{} is not a number of seconds", seconds))?;
+            options.cache = Some(Lifetime::Ttl(Duration::from_secs(seconds)));
+            Ok(())
+        }
+        ("remove", Some(path)) if !path.is_empty() => {
+            options.response.push(ResponseRule::Remove(JsonPath::parse(path)));
+            Ok(())
+        }
+        ("mask", Some(path)) if !path.is_empty() => {
+            options.response.push(ResponseRule::Mask(JsonPath::parse(path)));
+            Ok(())
+        }
+        ("limit", Some(limit)) => {
+            let (path, max_items) = match limit.rfind(':') {
+                Some(index) => (&limit[..index], &limit[index + 1..]),
+                None => ("", limit),
+            };
+            let max_items = max_items.parse().map_err(|_| format!("{} is not a number of items", max_items))?;
+            options.response.push(ResponseRule::Limit(JsonPath::parse(path), max_items));
+            Ok(())
+        }
+        ("generic_errors", None) => {
+            options.response.push(ResponseRule::GenericErrors);
+            Ok(())
+        }
+        _ => Err(format!("Unknown option {}", token)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_options() {
+        let list =
+            AllowedList::parse("ping\nchain_getBlockByHash cache=3600\nchain_getSeq cache=tip coalesce\n".as_bytes())
+                .unwrap();
+        assert!(list.contains("ping"));
+        assert!(list.contains("chain_getBlockByHash"));
+        assert_eq!(None, list.options("ping"));
+        assert_eq!(Some(Lifetime::Ttl(Duration::from_secs(3600))), list.options("chain_getBlockByHash").unwrap().cache);
+        assert_eq!(Some(Lifetime::UntilNextBlock), list.options("chain_getSeq").unwrap().cache);
+        assert!(list.options("chain_getSeq").unwrap().coalesce);
+        assert!(!list.options("chain_getBlockByHash").unwrap().coalesce);
+        assert!(list.depends_on_tip());
+    }
+
+    #[test]
+    fn add_and_remove_methods() {
+        let list = AllowedList::parse("ping\nchain_getSeq cache=tip\n".as_bytes()).unwrap();
+        let coalesce = MethodOptions::parse("coalesce").unwrap();
+        let list = list.with_method("chain_getBalance", coalesce.clone()).with_method("ping", coalesce.clone());
+        assert!(list.contains("chain_getBalance"));
+        assert_eq!(Some(&coalesce), list.options("ping"));
+        assert_eq!(3, list.methods().count());
+
+        let list = list.without_method("chain_getSeq");
+        assert!(!list.contains("chain_getSeq"));
+        assert!(!list.depends_on_tip());
+        assert_eq!("chain_getBalance coalesce\nping coalesce\n", list.to_string());
+    }
+
+    #[test]
+    fn write_in_the_format_of_the_file() {
+        let file = "chain_getBlockByHash cache=3600 remove=transactions.*.signature limit=transactions:100 \
+                    generic_errors\nchain_getSeq cache=tip coalesce\nmempool_getPendingTransactions limit=10\nping\n";
+        let list = AllowedList::parse(file.as_bytes()).unwrap();
+        assert_eq!(file, list.to_string());
+    }
+
+    #[test]
+    fn deny_methods() {
+        let file = "!personal_*\n!chain_getBalance\nchain_getBalance\nchain_getSeq\n\n# 10 requests\npersonal_sign\n";
+        let list = AllowedList::parse(file.as_bytes()).unwrap();
+        assert!(list.contains("chain_getSeq"));
+        assert!(!list.contains("chain_getBalance"));
+        assert!(!list.contains("personal_sign"));
+        assert!(!list.contains(""));
+        assert!(!list
+            .with_method("personal_unlockAccount", MethodOptions::default())
+            .contains("personal_unlockAccount"));
+        assert_eq!("!personal_*\n!chain_getBalance\nchain_getBalance\nchain_getSeq\npersonal_sign\n", list.to_string());
+        assert!(AllowedList::parse("!ping cache=1\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn monitor_denials() {
+        let file = "!chain_getBalance\n!chain_* monitor\nchain_getBalance\nchain_getSeq\nping\n";
+        let list = AllowedList::parse(file.as_bytes()).unwrap();
+        assert_eq!(None, list.denial("ping"));
+        assert_eq!(
+            Some(Denial {
+                reason: "chain_getSeq is denied by !chain_*".to_string(),
+                monitored: true,
+            }),
+            list.denial("chain_getSeq")
+        );
+        assert!(list.contains("chain_getSeq"));
+        assert_eq!(
+            Some(Denial {
+                reason: "chain_getBalance is denied by !chain_getBalance".to_string(),
+                monitored: false,
+            }),
+            list.denial("chain_getBalance")
+        );
+        assert_eq!(
+            Some(Denial {
+                reason: "chain_getText is not in the allowed list".to_string(),
+                monitored: false,
+            }),
+            list.denial("chain_getText")
+        );
+        assert_eq!(file, list.to_string());
+    }
+
+    #[test]
+    fn reject_unknown_option() {
+        match AllowedList::parse("ping\nchain_getBlockByHash ttl=1\n".as_bytes()) {
+            Err(Error::InvalidAllowedList {
+                line,
+                ..
+            }) => assert_eq!(2, line),
+            _ => panic!("The option must be rejected"),
+        }
+    }
+
+    #[test]
+    fn pick_the_method_set() {
+        let file = "@method-set perfect-hash\n!personal_*\nping\n";
+        let list = AllowedList::parse(file.as_bytes()).unwrap();
+        assert_eq!(MethodSetKind::PerfectHash, list.kind());
+        assert!(list.contains("ping"));
+        assert_eq!(MethodSetKind::PerfectHash, list.with_method("version", MethodOptions::default()).kind());
+        assert_eq!(file, list.to_string());
+        assert_eq!(MethodSetKind::Bisect, AllowedList::parse("ping\n".as_bytes()).unwrap().kind());
+        assert!(AllowedList::parse("@method-set fst\n".as_bytes()).is_err());
+        assert!(AllowedList::parse("@cache tip\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn update_keeps_the_comments_and_the_order() {
+        let file = "# Chain\nchain_getSeq   cache=tip\n\n!personal_*\nping\nchain_getBalance\n";
+        let list = AllowedList::parse(file.as_bytes())
+            .unwrap()
+            .with_method("chain_getBalance", MethodOptions::parse("cache=10").unwrap())
+            .with_method("account_getList", MethodOptions::default())
+            .without_method("ping");
+        assert_eq!(
+            "# Chain\nchain_getSeq   cache=tip\n\n!personal_*\nchain_getBalance cache=10\naccount_getList\n",
+            list.update(file)
+        );
+    }
+}
@@ -14,12 +14,19 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+#[cfg(feature = "serde")]
+use serde::de::{Deserialize, Deserializer};
+#[cfg(feature = "serde")]
+use serde::ser::{Serialize, Serializer};
 use std::borrow::Borrow;
 use std::cmp::Ordering;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
 use std::iter::{FromIterator, IntoIterator};
+use std::ops::{Bound, RangeBounds};
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct BisectSet<T> {
+    /// Sorted and deduplicated.
     items: Vec<T>,
 }
 
@@ -27,14 +34,35 @@ impl<T: Ord> FromIterator<T> for BisectSet<T> {
     fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
         let mut items: Vec<_> = iter.into_iter().collect();
         items.sort_unstable();
+        items.dedup();
         BisectSet {
             items,
         }
     }
 }
 
+impl<T> Default for BisectSet<T> {
+    fn default() -> Self {
+        BisectSet {
+            items: Vec::new(),
+        }
+    }
+}
+
 impl<T: std::cmp::Ord> BisectSet<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
     pub fn contains<Q>(&self, target: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Ord + ?Sized, {
+        self.position(target).is_ok()
+    }
+
+    /// The index of the target, or the index where it would be inserted.
+    fn position<Q>(&self, target: &Q) -> Result<usize, usize>
     where
         T: Borrow<Q>,
         Q: Ord + ?Sized, {
@@ -45,10 +73,161 @@ impl<T: std::cmp::Ord> BisectSet<T> {
             match Borrow::<Q>::borrow(&self.items[pivot]).cmp(target) {
                 Ordering::Less => left = pivot + 1,
                 Ordering::Greater => right = pivot,
-                Ordering::Equal => return true,
+                Ordering::Equal => return Ok(pivot),
+            }
+        }
+        Err(left)
+    }
+
+    /// Returns false if the item was already in the set.
+    pub fn insert(&mut self, item: T) -> bool {
+        match self.position(&item) {
+            Ok(_) => false,
+            Err(index) => {
+                self.items.insert(index, item);
+                true
+            }
+        }
+    }
+
+    /// Returns false if the item was not in the set.
+    pub fn remove<Q>(&mut self, target: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Ord + ?Sized, {
+        match self.position(target) {
+            Ok(index) => {
+                self.items.remove(index);
+                true
             }
+            Err(_) => false,
         }
-        false
+    }
+
+    /// Iterates the items in sorted order.
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.items.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Iterates the items in the range in sorted order. An empty or reversed range has no items.
+    pub fn range<Q, R>(&self, range: R) -> std::slice::Iter<'_, T>
+    where
+        T: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>, {
+        let start = match range.start_bound() {
+            Bound::Included(start) => self.position(start).unwrap_or_else(|index| index),
+            Bound::Excluded(start) => self.position(start).map(|index| index + 1).unwrap_or_else(|index| index),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => self.position(end).map(|index| index + 1).unwrap_or_else(|index| index),
+            Bound::Excluded(end) => self.position(end).unwrap_or_else(|index| index),
+            Bound::Unbounded => self.items.len(),
+        };
+        self.items[start..end.max(start)].iter()
+    }
+}
+
+impl<T: Ord + Clone> BisectSet<T> {
+    /// The items in either set.
+    pub fn union(&self, other: &Self) -> Self {
+        self.merge(other, true, true, true)
+    }
+
+    /// The items in both sets.
+    pub fn intersection(&self, other: &Self) -> Self {
+        self.merge(other, false, true, false)
+    }
+
+    /// The items in this set but not in the other.
+    pub fn difference(&self, other: &Self) -> Self {
+        self.merge(other, true, false, false)
+    }
+
+    /// Walks both sorted sets once, keeping the items only in this set, in both and only in the other as asked.
+    fn merge(&self, other: &Self, only_self: bool, both: bool, only_other: bool) -> Self {
+        let mut items = Vec::new();
+        let mut left = self.items.iter().peekable();
+        let mut right = other.items.iter().peekable();
+        loop {
+            let (item, keep) = match (left.peek(), right.peek()) {
+                (Some(l), Some(r)) => match l.cmp(r) {
+                    Ordering::Less => (left.next(), only_self),
+                    Ordering::Greater => (right.next(), only_other),
+                    Ordering::Equal => {
+                        right.next();
+                        (left.next(), both)
+                    }
+                },
+                (Some(_), None) => (left.next(), only_self),
+                (None, Some(_)) => (right.next(), only_other),
+                (None, None) => break,
+            };
+            if keep {
+                items.extend(item.cloned());
+            }
+        }
+        BisectSet {
+            items,
+        }
+    }
+}
+
+impl<T: Ord + AsRef<str>> BisectSet<T> {
+    /// Iterates the items that start with the prefix in sorted order.
+    pub fn starts_with(&self, prefix: &str) -> std::slice::Iter<'_, T> {
+        let mut left = 0;
+        let mut right = self.items.len();
+        while left != right {
+            let pivot = (left + right) / 2;
+            if self.items[pivot].as_ref() < prefix {
+                left = pivot + 1;
+            } else {
+                right = pivot;
+            }
+        }
+        let count = self.items[left..].iter().take_while(|item| item.as_ref().starts_with(prefix)).count();
+        self.items[left..left + count].iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a BisectSet<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.iter()
+    }
+}
+
+impl<T: Debug> Debug for BisectSet<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_set().entries(self.items.iter()).finish()
+    }
+}
+
+/// Serialized as a sorted sequence.
+#[cfg(feature = "serde")]
+impl<T: Serialize> Serialize for BisectSet<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.items.serialize(serializer)
+    }
+}
+
+/// Any sequence is accepted, and sorted and deduplicated.
+#[cfg(feature = "serde")]
+impl<'de, T: Deserialize<'de> + Ord> Deserialize<'de> for BisectSet<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::<T>::deserialize(deserializer).map(BisectSet::from_iter)
     }
 }
 
@@ -151,4 +330,83 @@ mod tests {
         assert!(allowed_list.contains("version"));
         assert!(!allowed_list.contains("non exist"));
     }
+
+    fn set(items: &[&'static str]) -> BisectSet<&'static str> {
+        items.iter().cloned().collect()
+    }
+
+    #[test]
+    fn from_iter_sorts_and_deduplicates() {
+        let allowed_list = set(&["version", "ping", "version", "chain_getSeq", "ping"]);
+        assert_eq!(3, allowed_list.len());
+        assert_eq!(vec!["chain_getSeq", "ping", "version"], allowed_list.iter().cloned().collect::<Vec<_>>());
+        assert_eq!(set(&["chain_getSeq", "ping", "version"]), allowed_list);
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let mut allowed_list = set(&["ping", "version"]);
+        assert_eq!(2, allowed_list.len());
+        assert!(allowed_list.insert("chain_getSeq"));
+        assert!(!allowed_list.insert("ping"));
+        assert_eq!(vec!["chain_getSeq", "ping", "version"], allowed_list.iter().cloned().collect::<Vec<_>>());
+        assert!(allowed_list.remove("ping"));
+        assert!(!allowed_list.remove("ping"));
+        assert!(!allowed_list.contains("ping"));
+        assert!(allowed_list.remove("chain_getSeq"));
+        assert!(allowed_list.remove("version"));
+        assert!(allowed_list.is_empty());
+        assert_eq!(BisectSet::new(), allowed_list);
+    }
+
+    #[test]
+    fn set_algebra() {
+        let left = set(&["chain_getSeq", "ping", "version"]);
+        let right = set(&["chain_getBalance", "ping"]);
+        assert_eq!(set(&["chain_getBalance", "chain_getSeq", "ping", "version"]), left.union(&right));
+        assert_eq!(set(&["ping"]), left.intersection(&right));
+        assert_eq!(set(&["chain_getSeq", "version"]), left.difference(&right));
+        assert_eq!(set(&["chain_getBalance"]), right.difference(&left));
+        assert_eq!(BisectSet::new(), left.difference(&left));
+    }
+
+    #[test]
+    fn range_and_prefix() {
+        let allowed_list = fixture();
+        assert_eq!(
+            vec!["chain_getShardIdByHash", "chain_getShardOwners", "chain_getShardRoot", "chain_getShardUsers"],
+            allowed_list.starts_with("chain_getShard").cloned().collect::<Vec<_>>()
+        );
+        assert_eq!(5, allowed_list.starts_with("mempool_").count());
+        assert_eq!(0, allowed_list.starts_with("personal_").count());
+        assert_eq!(40, allowed_list.starts_with("").count());
+
+        assert_eq!(
+            vec!["commitHash", "engine_getBlockReward"],
+            allowed_list.range("commitHash".."engine_getCoinbase").cloned().collect::<Vec<_>>()
+        );
+        assert_eq!(vec!["ping", "version"], allowed_list.range("p"..).cloned().collect::<Vec<_>>());
+        assert_eq!(
+            vec!["engine_getCoinbase"],
+            allowed_list
+                .range::<str, _>((Bound::Excluded("engine_getBlockReward"), Bound::Included("engine_getCoinbase")))
+                .cloned()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(0, allowed_list.range("version".."ping").count());
+    }
+
+    #[test]
+    fn debug() {
+        assert_eq!(r#"{"ping", "version"}"#, format!("{:?}", set(&["version", "ping"])));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let allowed_list = set(&["version", "ping"]);
+        assert_eq!(r#"["ping","version"]"#, serde_json::to_string(&allowed_list).unwrap());
+        let parsed: BisectSet<String> = serde_json::from_str(r#"["version","ping","version"]"#).unwrap();
+        assert_eq!(vec!["ping", "version"], parsed.iter().map(String::as_str).collect::<Vec<_>>());
+    }
 }
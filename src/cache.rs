@@ -0,0 +1,275 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use lru::LruCache;
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a response of a method stays valid.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Lifetime {
+    Ttl(Duration),
+    /// Valid until the best block of the upstream changes.
+    UntilNextBlock,
+}
+
+/// When a cached response becomes stale.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Expiry {
+    At(Instant),
+    /// Stale once the tip is no longer the given block number.
+    Tip(u64),
+}
+
+/// Identifies a cached response by the method and the canonicalised params.
+///
+/// `serde_json` keeps the keys of an object sorted, so the serialized params are the same regardless of the
+/// order in which the client wrote them.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Key {
+    method: String,
+    params: String,
+}
+
+impl Key {
+    pub fn new(method: &str, params: Option<&Value>) -> Self {
+        Key {
+            method: method.to_string(),
+            params: params.map(Value::to_string).unwrap_or_default(),
+        }
+    }
+}
+
+struct Entry {
+    /// The response without its `id`.
+    response: Value,
+    expiry: Expiry,
+}
+
+struct Entries {
+    lru: LruCache<Key, Entry>,
+    /// The best block number last seen by the tip watcher.
+    tip: Option<u64>,
+}
+
+impl Entries {
+    fn is_fresh(&self, expiry: Expiry) -> bool {
+        match expiry {
+            Expiry::At(instant) => instant > Instant::now(),
+            Expiry::Tip(tip) => self.tip == Some(tip),
+        }
+    }
+}
+
+pub struct Cache {
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Self {
+        Cache {
+            entries: Mutex::new(Entries {
+                lru: LruCache::new(capacity),
+                tip: None,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns when a response received from now on becomes stale.
+    ///
+    /// Responses that depend on the tip are not cached until the tip watcher has seen a block, and nothing is cached
+    /// if the capacity is 0.
+    pub fn expiry(&self, lifetime: Lifetime) -> Option<Expiry> {
+        let entries = self.entries.lock().unwrap();
+        if entries.lru.cap() == 0 {
+            return None
+        }
+        match lifetime {
+            Lifetime::Ttl(ttl) => Some(Expiry::At(Instant::now() + ttl)),
+            Lifetime::UntilNextBlock => entries.tip.map(Expiry::Tip),
+        }
+    }
+
+    /// The best block number of the upstream, once the tip watcher has seen a block.
+    pub fn tip(&self) -> Option<u64> {
+        self.entries.lock().unwrap().tip
+    }
+
+    /// Moves the tip and drops every response cached at the previous one.
+    pub fn set_tip(&self, tip: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.tip == Some(tip) {
+            return
+        }
+        entries.tip = Some(tip);
+        let stale: Vec<_> = entries
+            .lru
+            .iter()
+            .filter(|(_, entry)| match entry.expiry {
+                Expiry::Tip(_) => true,
+                Expiry::At(_) => false,
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            entries.lru.pop(&key);
+        }
+    }
+
+    /// Returns the cached response rewritten with the caller's `id`.
+    pub fn get(&self, key: &Key, id: &Value) -> Option<Vec<u8>> {
+        let response = {
+            let mut entries = self.entries.lock().unwrap();
+            match entries.lru.peek(key).map(|entry| entry.expiry) {
+                Some(expiry) if entries.is_fresh(expiry) => entries.lru.get(key).map(|entry| entry.response.clone()),
+                Some(_) => {
+                    entries.lru.pop(key);
+                    None
+                }
+                None => None,
+            }
+        };
+        match response {
+            Some(mut response) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                response["id"] = id.clone();
+                Some(serde_json::to_vec(&response).expect("Serializing a JSON value never fails"))
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Keeps the response until `expiry` if it is a successful JSON-RPC response.
+    pub fn insert(&self, key: Key, response: &[u8], expiry: Expiry) {
+        let mut response = match serde_json::from_slice::<Value>(response) {
+            Ok(response) => response,
+            Err(_) => return,
+        };
+        let object = match response.as_object_mut() {
+            Some(object) if object.contains_key("result") && !object.contains_key("error") => object,
+            _ => return,
+        };
+        object.remove("id");
+        let mut entries = self.entries.lock().unwrap();
+        // `LruCache::put` panics without capacity.
+        if entries.lru.cap() == 0 || !entries.is_fresh(expiry) {
+            return
+        }
+        entries.lru.put(key, Entry {
+            response,
+            expiry,
+        });
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn canonical_params() {
+        let left = json!([{"a": 1, "b": 2}]);
+        let right: Value = serde_json::from_str(r#"[{"b": 2, "a": 1}]"#).unwrap();
+        assert_eq!(Key::new("chain_getBlockByHash", Some(&left)), Key::new("chain_getBlockByHash", Some(&right)));
+        assert_ne!(Key::new("chain_getBlockByHash", Some(&left)), Key::new("chain_getTransaction", Some(&left)));
+    }
+
+    #[test]
+    fn zero_capacity_disables_the_cache() {
+        let cache = Cache::new(0);
+        assert_eq!(None, cache.expiry(Lifetime::Ttl(Duration::from_secs(1))));
+        let key = Key::new("ping", None);
+        cache.insert(
+            key.clone(),
+            br#"{"jsonrpc":"2.0","id":1,"result":"pong"}"#,
+            Expiry::At(Instant::now() + Duration::from_secs(1)),
+        );
+        assert_eq!(None, cache.get(&key, &json!(1)));
+    }
+
+    #[test]
+    fn rewrite_id() {
+        let cache = Cache::new(16);
+        let key = Key::new("chain_getNetworkId", None);
+        cache.insert(
+            key.clone(),
+            br#"{"jsonrpc":"2.0","result":"tc","id":1}"#,
+            Expiry::At(Instant::now() + Duration::from_secs(60)),
+        );
+
+        let response: Value = serde_json::from_slice(&cache.get(&key, &json!("abc")).unwrap()).unwrap();
+        assert_eq!(json!({"jsonrpc": "2.0", "result": "tc", "id": "abc"}), response);
+        assert_eq!(1, cache.hits());
+        assert_eq!(0, cache.misses());
+    }
+
+    #[test]
+    fn errors_are_not_cached() {
+        let cache = Cache::new(16);
+        let key = Key::new("chain_getNetworkId", None);
+        cache.insert(
+            key.clone(),
+            br#"{"jsonrpc":"2.0","error":{"code":-1},"id":1}"#,
+            Expiry::At(Instant::now() + Duration::from_secs(60)),
+        );
+        assert_eq!(None, cache.get(&key, &json!(1)));
+        assert_eq!(1, cache.misses());
+    }
+
+    #[test]
+    fn expired() {
+        let cache = Cache::new(16);
+        let key = Key::new("chain_getNetworkId", None);
+        cache.insert(key.clone(), br#"{"jsonrpc":"2.0","result":"tc","id":1}"#, Expiry::At(Instant::now()));
+        assert_eq!(None, cache.get(&key, &json!(1)));
+    }
+
+    #[test]
+    fn invalidate_on_new_block() {
+        let cache = Cache::new(16);
+        let key = Key::new("chain_getBestBlockNumber", None);
+        assert_eq!(None, cache.expiry(Lifetime::UntilNextBlock));
+
+        cache.set_tip(10);
+        let expiry = cache.expiry(Lifetime::UntilNextBlock).unwrap();
+        assert_eq!(Expiry::Tip(10), expiry);
+        cache.insert(key.clone(), br#"{"jsonrpc":"2.0","result":10,"id":1}"#, expiry);
+        assert!(cache.get(&key, &json!(1)).is_some());
+
+        cache.set_tip(11);
+        assert_eq!(None, cache.get(&key, &json!(1)));
+        cache.insert(key.clone(), br#"{"jsonrpc":"2.0","result":10,"id":1}"#, expiry);
+        assert_eq!(None, cache.get(&key, &json!(1)));
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::cache::Key;
+use futures::channel::oneshot;
+use hyper::header::CONTENT_LENGTH;
+use hyper::http::response::Parts;
+use hyper::{HeaderMap, Response, StatusCode};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// The upstream response shared with the callers waiting for the same request.
+#[derive(Clone)]
+pub struct Reply {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    /// Returns the response rewritten with the caller's `id`.
+    pub fn into_response(self, id: &Value) -> (Parts, Vec<u8>) {
+        let body = match serde_json::from_slice::<Value>(&self.body) {
+            Ok(mut response) if response.is_object() => {
+                response["id"] = id.clone();
+                serde_json::to_vec(&response).expect("Serializing a JSON value never fails")
+            }
+            _ => self.body,
+        };
+        let (mut parts, ()) = Response::new(()).into_parts();
+        parts.status = self.status;
+        parts.headers = self.headers;
+        parts.headers.remove(CONTENT_LENGTH);
+        (parts, body)
+    }
+}
+
+pub enum Role<'a> {
+    /// The caller has to send the request and complete it for the others.
+    Leader(Guard<'a>),
+    /// The same request is in flight. The receiver is canceled if the leader fails.
+    Follower(oneshot::Receiver<Reply>),
+}
+
+/// Deduplicates identical requests in flight.
+#[derive(Default)]
+pub struct Coalescer {
+    in_flight: Mutex<HashMap<Key, Vec<oneshot::Sender<Reply>>>>,
+}
+
+impl Coalescer {
+    pub fn join(&self, key: Key) -> Role<'_> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(waiters) = in_flight.get_mut(&key) {
+            let (sender, receiver) = oneshot::channel();
+            waiters.push(sender);
+            return Role::Follower(receiver)
+        }
+        in_flight.insert(key.clone(), Vec::new());
+        Role::Leader(Guard {
+            coalescer: self,
+            key,
+            reply: None,
+        })
+    }
+}
+
+/// Unregisters the request when the leader finishes, whether it succeeded or not.
+pub struct Guard<'a> {
+    coalescer: &'a Coalescer,
+    key: Key,
+    reply: Option<Reply>,
+}
+
+impl Guard<'_> {
+    pub fn complete(mut self, parts: &Parts, body: &[u8]) {
+        self.reply = Some(Reply {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.to_vec(),
+        });
+    }
+}
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        let waiters = self.coalescer.in_flight.lock().unwrap().remove(&self.key).unwrap_or_default();
+        if let Some(reply) = self.reply.take() {
+            for waiter in waiters {
+                // The waiter may have been gone.
+                let _ = waiter.send(reply.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use serde_json::json;
+
+    #[test]
+    fn followers_get_their_own_id() {
+        let coalescer = Coalescer::default();
+        let key = Key::new("chain_getBestBlockNumber", Some(&json!([])));
+        let leader = match coalescer.join(key.clone()) {
+            Role::Leader(guard) => guard,
+            Role::Follower(_) => panic!("The first caller must lead"),
+        };
+        let follower = match coalescer.join(key.clone()) {
+            Role::Follower(receiver) => receiver,
+            Role::Leader(_) => panic!("The request is in flight"),
+        };
+
+        let (parts, ()) = Response::new(()).into_parts();
+        leader.complete(&parts, br#"{"jsonrpc":"2.0","result":10,"id":1}"#);
+        let (_, body) = block_on(follower).unwrap().into_response(&json!(2));
+        let response: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json!({"jsonrpc": "2.0", "result": 10, "id": 2}), response);
+
+        match coalescer.join(key) {
+            Role::Leader(_) => {}
+            Role::Follower(_) => panic!("The request has been completed"),
+        };
+    }
+
+    #[test]
+    fn followers_are_canceled_when_leader_fails() {
+        let coalescer = Coalescer::default();
+        let key = Key::new("chain_getBestBlockNumber", Some(&json!([])));
+        let leader = coalescer.join(key.clone());
+        let follower = match coalescer.join(key) {
+            Role::Follower(receiver) => receiver,
+            Role::Leader(_) => panic!("The request is in flight"),
+        };
+        drop(leader);
+        assert!(block_on(follower).is_err());
+    }
+}
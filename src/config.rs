@@ -15,24 +15,216 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use super::{Error, Filter};
-use crate::bisect_set::BisectSet;
+use crate::access_log::{Access, AccessLog};
+use crate::cache::Cache;
+use crate::coalesce::Coalescer;
+use crate::health::Health;
+use crate::learn::Learner;
+use crate::metrics::Metrics;
+#[cfg(feature = "plugin")]
+use crate::plugin::WasmPlugin;
+use crate::policy::{AllowedListPolicy, Policy, ResponseFilter};
+use crate::rate_limit::RateLimiter;
+use crate::record::Recorder;
+use crate::shadow::Shadow;
+use crate::trace::Exporter;
 use futures::future;
+use hyper::header::HeaderName;
+use hyper::server::conn::AddrStream;
 use hyper::service::Service;
+use std::net::IpAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
 
+pub const DEFAULT_CACHE_SIZE: usize = 1024;
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 100 << 20;
+
+/// Called with every finished request, after the access log is written.
+pub type AccessHook = Box<dyn Fn(&Access) + Send + Sync>;
+
 pub struct Config {
     pub forward: hyper::Uri,
-    pub allowed_rpcs: BisectSet<String>,
+    /// The allowed list, which is also the first of the policies.
+    pub policy: Arc<AllowedListPolicy>,
+    pub policies: Vec<Arc<dyn Policy>>,
+    pub response_filters: Vec<Arc<dyn ResponseFilter>>,
+    /// The header that has the client identity set by the authenticating proxy in front of the filter.
+    pub identity_header: Option<HeaderName>,
+    pub cache: Cache,
+    /// The maximum size in bytes of an upstream response. A larger response is aborted.
+    pub max_response_size: usize,
+    pub coalescer: Coalescer,
+    pub metrics: Metrics,
+    pub health: Health,
+    pub access_log: Option<AccessLog>,
+    pub trace_exporter: Option<Exporter>,
+    pub hooks: Vec<AccessHook>,
+    /// Records the methods and allows them regardless of the allowed list in the learn mode.
+    pub learner: Option<Arc<Learner>>,
+    /// Forwards the requests that the policies deny, and reports them as would-block.
+    pub monitor: bool,
+    pub shadow: Option<Shadow>,
+    pub recorder: Option<Recorder>,
+    pub rate_limiter: Option<RateLimiter>,
+}
+
+/// Builds the filter in front of an upstream.
+///
+/// The built `ServiceMaker` can be served by `hyper::Server`, or make a `Filter` for each peer in process. The builder
+/// doesn't spawn any task; spawn `tip::watch` and `health::watch` with its config to use `cache=tip` and `/readyz`.
+pub struct FilterBuilder {
+    forward: hyper::Uri,
+    policy: AllowedListPolicy,
+    policies: Vec<Arc<dyn Policy>>,
+    response_filters: Vec<Arc<dyn ResponseFilter>>,
+    identity_header: Option<HeaderName>,
+    cache_size: usize,
+    max_response_size: usize,
+    access_log: Option<AccessLog>,
+    trace_exporter: Option<Exporter>,
+    hooks: Vec<AccessHook>,
+    learner: Option<Arc<Learner>>,
+    monitor: bool,
+    shadow: Option<Shadow>,
+    recorder: Option<Recorder>,
+    rate_limiter: Option<RateLimiter>,
 }
 
-impl Config {
-    pub fn new(forward: hyper::Uri, allowed_rpcs: BisectSet<String>) -> Self {
-        Config {
+impl FilterBuilder {
+    pub fn new(forward: hyper::Uri, policy: AllowedListPolicy) -> Self {
+        FilterBuilder {
             forward,
-            allowed_rpcs,
+            policy,
+            policies: Vec::new(),
+            response_filters: Vec::new(),
+            identity_header: None,
+            cache_size: DEFAULT_CACHE_SIZE,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            access_log: None,
+            trace_exporter: None,
+            hooks: Vec::new(),
+            learner: None,
+            monitor: false,
+            shadow: None,
+            recorder: None,
+            rate_limiter: None,
+        }
+    }
+
+    /// Adds a policy asked after the allowed list and the policies added before.
+    pub fn policy<P: Policy + 'static>(mut self, policy: P) -> Self {
+        self.policies.push(Arc::new(policy));
+        self
+    }
+
+    /// Adds a filter asked after the response filters added before.
+    pub fn response_filter<F: ResponseFilter + 'static>(mut self, response_filter: F) -> Self {
+        self.response_filters.push(Arc::new(response_filter));
+        self
+    }
+
+    /// Adds the plugin as a policy, and as a response filter if it exports `filter_response`, so that the responses
+    /// of the other plugins are streamed. A reload can't make the plugin a response filter.
+    #[cfg(feature = "plugin")]
+    pub fn plugin(mut self, plugin: WasmPlugin) -> Self {
+        let plugin = Arc::new(plugin);
+        self.policies.push(Arc::clone(&plugin) as Arc<dyn Policy>);
+        if plugin.filters_responses() {
+            self.response_filters.push(plugin);
         }
+        self
+    }
+
+    pub fn identity_header(mut self, identity_header: HeaderName) -> Self {
+        self.identity_header = Some(identity_header);
+        self
+    }
+
+    /// The maximum number of cached responses. 0 disables the cache.
+    pub fn cache_size(mut self, cache_size: usize) -> Self {
+        self.cache_size = cache_size;
+        self
+    }
+
+    /// The maximum size in bytes of an upstream response.
+    pub fn max_response_size(mut self, max_response_size: usize) -> Self {
+        self.max_response_size = max_response_size;
+        self
+    }
+
+    pub fn access_log(mut self, access_log: AccessLog) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    pub fn trace_exporter(mut self, trace_exporter: Exporter) -> Self {
+        self.trace_exporter = Some(trace_exporter);
+        self
+    }
+
+    /// Runs in the learn mode, which allows every method that the other policies allow, and records the calls.
+    pub fn learner(mut self, learner: Arc<Learner>) -> Self {
+        self.learner = Some(learner);
+        self
+    }
+
+    /// Runs in the monitor mode, which forwards the requests that the policies deny, and logs and counts them as
+    /// would-block. `!` lines with the `monitor` option of the allowed list are in the monitor mode by themselves.
+    pub fn monitor(mut self, monitor: bool) -> Self {
+        self.monitor = monitor;
+        self
+    }
+
+    /// Mirrors the allowed requests to the shadow upstream.
+    pub fn shadow(mut self, shadow: Shadow) -> Self {
+        self.shadow = Some(shadow);
+        self
+    }
+
+    /// Records the allowed requests and the responses of the upstream.
+    pub fn recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Answers 429 to a peer that sends more than `per_second` requests in a second.
+    pub fn rate_limit(mut self, per_second: u32) -> Self {
+        self.rate_limiter = Some(RateLimiter::new(per_second));
+        self
+    }
+
+    pub fn on_access<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Access) + Send + Sync + 'static, {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    pub fn build(self) -> ServiceMaker {
+        let metrics = Metrics::new(self.policy.allowed_list().methods());
+        let policy = Arc::new(self.policy);
+        let policies = std::iter::once(Arc::clone(&policy) as Arc<dyn Policy>).chain(self.policies).collect();
+        ServiceMaker::new(Arc::new(Config {
+            forward: self.forward,
+            policy,
+            policies,
+            response_filters: self.response_filters,
+            identity_header: self.identity_header,
+            cache: Cache::new(self.cache_size),
+            max_response_size: self.max_response_size,
+            coalescer: Coalescer::default(),
+            metrics,
+            health: Health::default(),
+            access_log: self.access_log,
+            trace_exporter: self.trace_exporter,
+            hooks: self.hooks,
+            learner: self.learner,
+            monitor: self.monitor,
+            shadow: self.shadow,
+            recorder: self.recorder,
+            rate_limiter: self.rate_limiter,
+        }))
     }
 }
 
@@ -48,9 +240,19 @@ impl ServiceMaker {
             counter: AtomicU64::new(0),
         }
     }
+
+    pub fn config(&self) -> &Arc<Config> {
+        &self.config
+    }
+
+    /// Makes the service for a connection from `peer`.
+    pub fn filter(&self, peer: IpAddr) -> Filter {
+        let seq = self.counter.fetch_add(1, Ordering::SeqCst);
+        Filter::new(Arc::clone(&self.config), seq, peer)
+    }
 }
 
-impl<T> Service<T> for ServiceMaker {
+impl Service<&AddrStream> for ServiceMaker {
     type Response = Filter;
     type Error = Error;
     type Future = future::Ready<Result<Self::Response, Self::Error>>;
@@ -59,9 +261,38 @@ impl<T> Service<T> for ServiceMaker {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, _: T) -> Self::Future {
-        let config = Arc::clone(&self.config);
-        let seq = self.counter.fetch_add(1, Ordering::SeqCst);
-        future::ok(Filter::new(config, seq))
+    fn call(&mut self, stream: &AddrStream) -> Self::Future {
+        future::ok(self.filter(stream.remote_addr().ip()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{policy, post, PEER};
+    use hyper::{Body, Request, StatusCode};
+    use std::sync::atomic::AtomicUsize;
+
+    #[tokio::test]
+    async fn filter_in_process() {
+        let accesses = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&accesses);
+        let maker = FilterBuilder::new("http://127.0.0.1:1".parse().unwrap(), policy("ping"))
+            .on_access(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .build();
+        let mut filter = maker.filter(PEER);
+
+        let response = filter.call(Request::get("/healthz").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        match filter.call(post(r#"{"jsonrpc":"2.0","id":1,"method":"chain_sendSignedTransaction"}"#)).await {
+            Err(Error::Denied {
+                method,
+                ..
+            }) => assert_eq!("chain_sendSignedTransaction", method),
+            _ => panic!("The method must be blocked"),
+        }
+        assert_eq!(1, accesses.load(Ordering::SeqCst));
     }
 }
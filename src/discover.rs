@@ -0,0 +1,130 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Compares an allowed list with the methods that the upstream has.
+
+use super::Error;
+use crate::allowed_list::AllowedList;
+use crate::filter::collect_body;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Client, Request};
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+const DISCOVER_REQUEST: &str = r#"{"jsonrpc":"2.0","id":0,"method":"rpc.discover","params":[]}"#;
+
+/// Asks the upstream for its methods with the `rpc.discover` of OpenRPC.
+pub async fn methods(forward: &hyper::Uri) -> Result<BTreeSet<String>, Error> {
+    let request = Request::post(forward.clone())
+        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+        .body(Body::from(DISCOVER_REQUEST))?;
+    let response = Client::new().request(request).await?;
+    let buffer = collect_body(response.into_body()).await?;
+    let response = serde_json::from_slice::<Value>(&buffer)?;
+    methods_of(&response["result"]).ok_or_else(|| Error::UnexpectedResponse(response.to_string()))
+}
+
+/// Reads a manifest of the methods of the upstream.
+///
+/// It's a saved `rpc.discover` response or its result, a JSON array of the names, or a name per line.
+pub fn parse_manifest(buffer: &[u8]) -> Result<BTreeSet<String>, Error> {
+    match serde_json::from_slice::<Value>(buffer) {
+        Ok(manifest) => methods_of(manifest.get("result").unwrap_or(&manifest))
+            .ok_or_else(|| Error::UnexpectedResponse(manifest.to_string())),
+        Err(_) => Ok(String::from_utf8_lossy(buffer)
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(ToString::to_string)
+            .collect()),
+    }
+}
+
+/// Reads an OpenRPC document or an array of the names.
+fn methods_of(value: &Value) -> Option<BTreeSet<String>> {
+    let methods = match value {
+        Value::Object(document) => document.get("methods")?.as_array()?,
+        Value::Array(methods) => methods,
+        _ => return None,
+    };
+    methods
+        .iter()
+        .map(|method| match method {
+            Value::String(name) => Some(name.clone()),
+            Value::Object(method) => method.get("name")?.as_str().map(ToString::to_string),
+            _ => None,
+        })
+        .collect()
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Diff {
+    /// The allowed methods that the upstream doesn't have.
+    pub missing: Vec<String>,
+    /// The methods of the upstream that the allowed list neither allows nor denies.
+    pub unlisted: Vec<String>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.unlisted.is_empty()
+    }
+}
+
+pub fn diff(allowed_list: &AllowedList, upstream: &BTreeSet<String>) -> Diff {
+    Diff {
+        missing: allowed_list
+            .methods()
+            .filter(|method| allowed_list.contains(method) && !upstream.contains(*method))
+            .map(ToString::to_string)
+            .collect(),
+        unlisted: upstream
+            .iter()
+            .filter(|method| !allowed_list.contains(method) && !allowed_list.is_denied(method))
+            .cloned()
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_every_manifest_format() {
+        let expected: BTreeSet<String> = vec!["chain_getSeq".to_string(), "ping".to_string()].into_iter().collect();
+        let openrpc = br#"{"openrpc":"1.2.6","methods":[{"name":"ping","params":[]},{"name":"chain_getSeq"}]}"#;
+        assert_eq!(expected, parse_manifest(openrpc).unwrap());
+        let response = br#"{"jsonrpc":"2.0","id":0,"result":{"methods":[{"name":"ping"},{"name":"chain_getSeq"}]}}"#;
+        assert_eq!(expected, parse_manifest(response).unwrap());
+        assert_eq!(expected, parse_manifest(br#"["ping","chain_getSeq"]"#).unwrap());
+        assert_eq!(expected, parse_manifest(b"ping\n\n  chain_getSeq\n").unwrap());
+        assert!(parse_manifest(br#"{"methods":[1]}"#).is_err());
+    }
+
+    #[test]
+    fn compare_with_the_upstream() {
+        let allowed_list = AllowedList::parse("!personal_*\nping\nchain_getSeq\nchain_getText\n".as_bytes()).unwrap();
+        let upstream = parse_manifest(b"ping\nchain_getSeq\nchain_getBalance\npersonal_sign\n").unwrap();
+        assert_eq!(
+            Diff {
+                missing: vec!["chain_getText".to_string()],
+                unlisted: vec!["chain_getBalance".to_string()],
+            },
+            diff(&allowed_list, &upstream)
+        );
+    }
+}
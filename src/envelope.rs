@@ -0,0 +1,213 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Reads the method of a JSON-RPC request without building the rest of it.
+//!
+//! The other members, including the params, are only tokenized, so a large request costs no allocation. The method is
+//! borrowed from the buffer unless it has escapes.
+
+use super::Error;
+use serde::de::{Deserialize, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use std::borrow::Cow;
+use std::fmt::{Formatter, Result as FmtResult};
+
+/// Returns the method of the request in `buffer`, which must be valid JSON.
+pub fn method(buffer: &[u8]) -> Result<Cow<'_, str>, Error> {
+    match serde_json::from_slice::<Envelope<'_>>(buffer)?.method {
+        Some(MethodValue::String(method)) => Ok(method),
+        Some(MethodValue::Other) => Err(Error::MethodIsNotString),
+        None => Err(Error::MethodIsNotDefined),
+    }
+}
+
+#[derive(Default)]
+struct Envelope<'a> {
+    method: Option<MethodValue<'a>>,
+}
+
+enum MethodValue<'a> {
+    String(Cow<'a, str>),
+    Other,
+}
+
+enum Member {
+    Method,
+    Other,
+}
+
+impl<'de> Deserialize<'de> for Envelope<'de> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EnvelopeVisitor;
+
+        impl<'de> Visitor<'de> for EnvelopeVisitor {
+            type Value = Envelope<'de>;
+
+            fn expecting(&self, f: &mut Formatter<'_>) -> FmtResult {
+                write!(f, "a JSON-RPC request")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut method = None;
+                while let Some(member) = map.next_key::<Member>()? {
+                    match member {
+                        // The last one wins, as in a `serde_json::Value`.
+                        Member::Method => method = Some(map.next_value()?),
+                        Member::Other => {
+                            map.next_value::<IgnoredAny>()?;
+                        }
+                    }
+                }
+                Ok(Envelope {
+                    method,
+                })
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+                IgnoredAny.visit_seq(seq)?;
+                Ok(Envelope::default())
+            }
+
+            fn visit_bool<E>(self, _: bool) -> Result<Self::Value, E> {
+                Ok(Envelope::default())
+            }
+
+            fn visit_i64<E>(self, _: i64) -> Result<Self::Value, E> {
+                Ok(Envelope::default())
+            }
+
+            fn visit_u64<E>(self, _: u64) -> Result<Self::Value, E> {
+                Ok(Envelope::default())
+            }
+
+            fn visit_f64<E>(self, _: f64) -> Result<Self::Value, E> {
+                Ok(Envelope::default())
+            }
+
+            fn visit_str<E>(self, _: &str) -> Result<Self::Value, E> {
+                Ok(Envelope::default())
+            }
+
+            fn visit_unit<E>(self) -> Result<Self::Value, E> {
+                Ok(Envelope::default())
+            }
+        }
+
+        deserializer.deserialize_any(EnvelopeVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for MethodValue<'de> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MethodVisitor;
+
+        impl<'de> Visitor<'de> for MethodVisitor {
+            type Value = MethodValue<'de>;
+
+            fn expecting(&self, f: &mut Formatter<'_>) -> FmtResult {
+                write!(f, "a method")
+            }
+
+            fn visit_borrowed_str<E>(self, method: &'de str) -> Result<Self::Value, E> {
+                Ok(MethodValue::String(Cow::Borrowed(method)))
+            }
+
+            fn visit_str<E>(self, method: &str) -> Result<Self::Value, E> {
+                Ok(MethodValue::String(Cow::Owned(method.to_string())))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                IgnoredAny.visit_map(map)?;
+                Ok(MethodValue::Other)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+                IgnoredAny.visit_seq(seq)?;
+                Ok(MethodValue::Other)
+            }
+
+            fn visit_bool<E>(self, _: bool) -> Result<Self::Value, E> {
+                Ok(MethodValue::Other)
+            }
+
+            fn visit_i64<E>(self, _: i64) -> Result<Self::Value, E> {
+                Ok(MethodValue::Other)
+            }
+
+            fn visit_u64<E>(self, _: u64) -> Result<Self::Value, E> {
+                Ok(MethodValue::Other)
+            }
+
+            fn visit_f64<E>(self, _: f64) -> Result<Self::Value, E> {
+                Ok(MethodValue::Other)
+            }
+
+            fn visit_unit<E>(self) -> Result<Self::Value, E> {
+                Ok(MethodValue::Other)
+            }
+        }
+
+        deserializer.deserialize_any(MethodVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for Member {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MemberVisitor;
+
+        impl<'de> Visitor<'de> for MemberVisitor {
+            type Value = Member;
+
+            fn expecting(&self, f: &mut Formatter<'_>) -> FmtResult {
+                write!(f, "a member name")
+            }
+
+            fn visit_str<E>(self, name: &str) -> Result<Self::Value, E> {
+                Ok(if name == "method" {
+                    Member::Method
+                } else {
+                    Member::Other
+                })
+            }
+        }
+
+        deserializer.deserialize_str(MemberVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_the_method() {
+        let request = br#"{"jsonrpc":"2.0","params":[{"method":"nested"}],"id":1,"method":"ping"}"#;
+        match method(request).unwrap() {
+            Cow::Borrowed(method) => assert_eq!("ping", method),
+            Cow::Owned(_) => panic!("The method must be borrowed"),
+        }
+        assert_eq!("chain_\"get\"", method(br#"{"method":"chain_\"get\""}"#).unwrap());
+    }
+
+    #[test]
+    fn reject_what_a_full_parse_rejects() {
+        let error = |request: &[u8]| method(request).unwrap_err().to_string();
+        assert_eq!(Error::MethodIsNotDefined.to_string(), error(br#"{"jsonrpc":"2.0","id":1}"#));
+        assert_eq!(Error::MethodIsNotDefined.to_string(), error(br#"[{"method":"ping"}]"#));
+        assert_eq!(Error::MethodIsNotString.to_string(), error(br#"{"method":["ping"]}"#));
+        assert!(method(br#"{"method":"ping"} trailing"#).is_err());
+        assert!(method(br#"{"method":"ping","params":[1,}"#).is_err());
+    }
+}
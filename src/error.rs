@@ -19,15 +19,33 @@ use hyper::Error as HyperError;
 use serde_json::Error as SerdeError;
 use std::error::Error as StdError;
 use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::Error as IoError;
 
 #[derive(Debug)]
 pub enum Error {
     Hyper(HyperError),
     HyperHttp(HyperHttpError),
     Serde(SerdeError),
-    NotAllowedMethod(String),
+    Io(IoError),
+    InvalidAllowedList {
+        line: usize,
+        reason: String,
+    },
+    InvalidRecord {
+        line: usize,
+        reason: String,
+    },
+    Denied {
+        method: String,
+        reason: String,
+    },
     MethodIsNotString,
     MethodIsNotDefined,
+    UnexpectedResponse(String),
+    /// The upstream response is larger than the given number of bytes.
+    ResponseTooLarge(usize),
+    Script(String),
+    Plugin(String),
 }
 
 impl StdError for Error {}
@@ -38,6 +56,12 @@ impl From<SerdeError> for Error {
     }
 }
 
+impl From<IoError> for Error {
+    fn from(err: IoError) -> Self {
+        Error::Io(err)
+    }
+}
+
 impl From<HyperError> for Error {
     fn from(err: HyperError) -> Self {
         Error::Hyper(err)
@@ -56,9 +80,25 @@ impl Display for Error {
             Error::Hyper(err) => write!(f, "{}", err),
             Error::HyperHttp(err) => write!(f, "{}", err),
             Error::Serde(err) => write!(f, "{}", err),
-            Error::NotAllowedMethod(method) => write!(f, "{} is not allowed method", method),
+            Error::Io(err) => write!(f, "{}", err),
+            Error::InvalidAllowedList {
+                line,
+                reason,
+            } => write!(f, "Invalid allowed list at line {}: {}", line, reason),
+            Error::InvalidRecord {
+                line,
+                reason,
+            } => write!(f, "Invalid record at line {}: {}", line, reason),
+            Error::Denied {
+                method,
+                reason,
+            } => write!(f, "{} is denied: {}", method, reason),
             Error::MethodIsNotString => write!(f, "Method is not a string"),
             Error::MethodIsNotDefined => write!(f, "Method is not defined"),
+            Error::UnexpectedResponse(response) => write!(f, "Unexpected response: {}", response),
+            Error::ResponseTooLarge(max_size) => write!(f, "The response is larger than {} bytes", max_size),
+            Error::Script(err) => write!(f, "Script error: {}", err),
+            Error::Plugin(err) => write!(f, "Plugin error: {}", err),
         }
     }
 }
@@ -15,35 +15,57 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use super::Error;
-use crate::bisect_set::BisectSet;
+use crate::access_log::Access;
+use crate::allowed_list::{AllowedList, MethodOptions};
+use crate::cache::Key;
+use crate::coalesce::Role;
 use crate::config::Config;
-use futures::TryStreamExt;
+use crate::envelope;
+use crate::health;
+use crate::metrics::Outcome;
+use crate::policy::{Decision, RequestInfo};
+use crate::redact::{self, ResponseRule};
+use crate::trace::{random_id, SpanKind, Trace};
+use futures::{future, TryStreamExt};
 use hyper::header::{
-    HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    CONTENT_LENGTH, CONTENT_TYPE,
 };
+use hyper::http::request::Parts;
+use hyper::http::response::Parts as ResponseParts;
 use hyper::service::Service;
 use hyper::{Body, Client, Method, Request, Response, StatusCode};
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
+use serde_json::{json, Value};
+use std::borrow::Cow;
 use std::future::Future;
+use std::net::IpAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Instant;
 
 pub struct Filter {
     config: Arc<Config>,
     seq: u64,
+    peer: IpAddr,
 }
 
 impl Filter {
-    pub fn new(config: Arc<Config>, seq: u64) -> Self {
+    pub fn new(config: Arc<Config>, seq: u64, peer: IpAddr) -> Self {
         Filter {
             config,
             seq,
+            peer,
         }
     }
 }
 
-type DynFuture<Output> = Pin<Box<dyn Future<Output = Output> + Send>>;
+const X_REQUEST_ID: &str = "x-request-id";
+const TRACEPARENT: &str = "traceparent";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+pub type DynFuture<Output> = Pin<Box<dyn Future<Output = Output> + Send>>;
 
 impl Service<Request<Body>> for Filter {
     type Response = Response<Body>;
@@ -55,7 +77,31 @@ impl Service<Request<Body>> for Filter {
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
-        async fn run(config: Arc<Config>, seq: u64, req: Request<Body>) -> Result<Response<Body>, Error> {
+        async fn run(
+            config: &Config,
+            access: &mut Access,
+            trace: &mut Trace,
+            req: Request<Body>,
+        ) -> Result<Response<Body>, Error> {
+            let seq = access.seq;
+            if config.policy.is_banned(access.peer) {
+                info!("seq: {}, banned peer: {}", seq, access.peer);
+                access.outcome = Some(Outcome::Blocked);
+                access.rule = Some("banned peer".to_string());
+                return Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .header(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"))
+                    .body(Body::empty())?)
+            }
+            if let Some(rate_limiter) = config.rate_limiter.as_ref().filter(|limiter| !limiter.acquire(access.peer)) {
+                info!("seq: {}, rate limited peer: {}, limit: {}/s", seq, access.peer, rate_limiter.per_second());
+                access.outcome = Some(Outcome::Blocked);
+                access.rule = Some("rate limit".to_string());
+                return Ok(Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .header(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"))
+                    .body(Body::empty())?)
+            }
             let (header, body) = req.into_parts();
             if Method::POST != header.method && Method::OPTIONS != header.method {
                 info!("seq: {}, Invalid method: {}", seq, header.method);
@@ -77,38 +123,606 @@ impl Service<Request<Body>> for Filter {
                     .body(Body::from(""))?)
             }
 
+            let span = trace.open("read_body", SpanKind::Internal);
             let buffer = collect_body(body).await?;
+            trace.close(span, vec![("bytes", json!(buffer.len()))]);
             trace!("seq: {}, bytes: {}", seq, String::from_utf8_lossy(&buffer));
-            filter_allowed_request(&buffer, &config.allowed_rpcs, seq)?;
+            access.request_bytes = buffer.len();
+            config.metrics.observe_request_bytes(buffer.len());
+            let identity = config
+                .identity_header
+                .as_ref()
+                .and_then(|name| header.headers.get(name))
+                .and_then(|identity| identity.to_str().ok());
+            access.identity = identity.map(ToString::to_string);
+            if let Some(identity) = identity.filter(|identity| config.policy.is_identity_banned(identity)) {
+                info!("seq: {}, banned identity: {}", seq, identity);
+                access.outcome = Some(Outcome::Blocked);
+                access.rule = Some("banned identity".to_string());
+                return Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .header(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"))
+                    .body(Body::empty())?)
+            }
+            let span = trace.open("filter", SpanKind::Internal);
+            let checked = check_request(config, &buffer, access.peer, &header.headers, identity, seq);
+            trace.close(span, vec![("allowed", json!(checked.is_ok()))]);
+            let Checked {
+                method,
+                request,
+                rewritten,
+                would_block,
+            } = match checked {
+                Ok(checked) => checked,
+                Err(err) => {
+                    if let Error::Denied {
+                        method,
+                        reason,
+                    } = &err
+                    {
+                        access.method = Some(method.clone());
+                        access.rule = Some(reason.clone());
+                        access.outcome = Some(Outcome::Blocked);
+                    }
+                    return Err(err)
+                }
+            };
+            if let Some(reason) = would_block {
+                config.metrics.would_block(Some(&method));
+                access.would_block = Some(reason);
+            }
+            let headers = if config.response_filters.is_empty() {
+                HeaderMap::new()
+            } else {
+                header.headers.clone()
+            };
+            let mut header = header;
+            let method = method.into_owned();
+            let request = request.unwrap_or(Value::Null);
+            let buffer = if rewritten {
+                header.headers.remove(CONTENT_LENGTH);
+                serde_json::to_vec(&request)?
+            } else {
+                buffer
+            };
+            let allowed_list = config.policy.allowed_list();
+            let method = &method[..];
+            access.method = Some(method.to_string());
+            access.rule = Some(method.to_string());
+            let default_options = MethodOptions::default();
+            let options = allowed_list.options(method).unwrap_or(&default_options);
+            let cache = options
+                .cache
+                .and_then(|lifetime| config.cache.expiry(lifetime))
+                .map(|expiry| (Key::new(method, request.get("params")), expiry));
+            let id = request.get("id").unwrap_or(&Value::Null);
+            let identity = access.identity.clone();
+            let info = RequestInfo {
+                method,
+                request: &request,
+                peer: access.peer,
+                headers: &headers,
+                identity: identity.as_ref().map(|identity| &identity[..]),
+                tip: config.cache.tip(),
+            };
+            let primary_response = config
+                .shadow
+                .as_ref()
+                .filter(|shadow| shadow.sample(method))
+                .and_then(|shadow| shadow.mirror(seq, method, buffer.clone(), config.max_response_size));
+            let recorded_request = config.recorder.as_ref().map(|_| buffer.clone());
+            if let Some((key, _)) = &cache {
+                let cached = config.cache.get(key, id);
+                debug!(
+                    "seq: {}, cache {}, hits: {}, misses: {}",
+                    seq,
+                    if cached.is_some() {
+                        "hit"
+                    } else {
+                        "miss"
+                    },
+                    config.cache.hits(),
+                    config.cache.misses()
+                );
+                if let Some(cached) = cached {
+                    access.outcome = Some(Outcome::Allowed);
+                    if let Some(primary_response) = primary_response {
+                        let _ = primary_response.send(cached.clone());
+                    }
+                    if let (Some(recorder), Some(request)) = (&config.recorder, &recorded_request) {
+                        recorder.record(method, request, StatusCode::OK, &cached, access.latency());
+                    }
+                    let (cached, _) = check_response(config, access, trace, &info, &options.response, cached)?;
+                    access.response_bytes = cached.len();
+                    config.metrics.observe_response_bytes(cached.len());
+                    return Ok(Response::builder()
+                        .status(StatusCode::OK)
+                        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+                        .header(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"))
+                        .body(Body::from(cached))?)
+                }
+            }
 
-            let mut req = Request::from_parts(header, Body::from(buffer));
-            *req.uri_mut() = config.forward.clone();
+            // A response compared with the one of the shadow upstream or recorded has to be read whole.
+            if cache.is_none()
+                && !options.coalesce
+                && options.response.is_empty()
+                && config.response_filters.is_empty()
+                && primary_response.is_none()
+                && recorded_request.is_none()
+            {
+                let (parts, body) = send(config, header, buffer, trace).await?;
+                access.outcome = Some(Outcome::Allowed);
+                access.upstream_status = Some(parts.status);
+                if let Some(length) = content_length(&parts.headers) {
+                    access.response_bytes = length;
+                    config.metrics.observe_response_bytes(length);
+                }
+                return Ok(Response::from_parts(parts, stream_body(body, config.max_response_size)))
+            }
 
-            let mut response = Client::new().request(req).await?;
-            response.headers_mut().insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
-            let (parts, body) = response.into_parts();
-            let buffer = collect_body(body).await?;
-            trace!("seq: {}, forward, bytes: {}", seq, String::from_utf8_lossy(&buffer));
+            let result = async {
+                if !options.coalesce {
+                    return forward(config, header, buffer, seq, trace).await
+                }
+                match config.coalescer.join(Key::new(method, request.get("params"))) {
+                    Role::Leader(guard) => {
+                        let (parts, buffer) = forward(config, header, buffer, seq, trace).await?;
+                        guard.complete(&parts, &buffer);
+                        Ok((parts, buffer))
+                    }
+                    Role::Follower(reply) => match reply.await {
+                        Ok(reply) => {
+                            debug!("seq: {}, coalesced", seq);
+                            config.metrics.coalesced();
+                            Ok(reply.into_response(id))
+                        }
+                        Err(_) => forward(config, header, buffer, seq, trace).await,
+                    },
+                }
+            }
+            .await;
+            let (mut parts, buffer) = result?;
+            if let Some(primary_response) = primary_response {
+                let _ = primary_response.send(buffer.clone());
+            }
+            access.outcome = Some(Outcome::Allowed);
+            access.upstream_status = Some(parts.status);
+            if let Some((key, expiry)) = cache {
+                if parts.status.is_success() {
+                    config.cache.insert(key, &buffer, expiry);
+                }
+            }
+            // The response of the upstream is recorded, so that a replay against the upstream compares the same.
+            if let (Some(recorder), Some(request)) = (&config.recorder, &recorded_request) {
+                recorder.record(method, request, parts.status, &buffer, access.latency());
+            }
+            let (buffer, rewritten) = check_response(config, access, trace, &info, &options.response, buffer)?;
+            if rewritten {
+                parts.headers.remove(CONTENT_LENGTH);
+            }
+            access.response_bytes = buffer.len();
+            config.metrics.observe_response_bytes(buffer.len());
             Ok(Response::from_parts(parts, Body::from(buffer)))
         }
 
-        Box::pin(run(Arc::clone(&self.config), self.seq, req))
+        let config = Arc::clone(&self.config);
+        let (seq, peer) = (self.seq, self.peer);
+        Box::pin(async move {
+            if let Some(response) = health::probe(&config.health, &req) {
+                return response
+            }
+
+            let _in_flight = config.metrics.request_started();
+            let mut req = req;
+            let request_id = match req.headers().get(X_REQUEST_ID) {
+                Some(request_id) if !request_id.is_empty() && request_id.len() <= MAX_REQUEST_ID_LEN => {
+                    request_id.clone()
+                }
+                _ => HeaderValue::from_str(&random_id(16)).expect("A hex string is a valid header value"),
+            };
+            req.headers_mut().insert(X_REQUEST_ID, request_id.clone());
+            let mut access = Access::new(seq, peer, request_id.to_str().unwrap_or_default().to_string());
+            let mut trace = Trace::start(req.headers().get(TRACEPARENT));
+            trace.set_attribute("http.method", json!(req.method().as_str()));
+
+            let mut result = run(&config, &mut access, &mut trace, req).await;
+            if result.is_err() && access.outcome != Some(Outcome::Blocked) {
+                access.outcome = Some(Outcome::Error);
+            }
+            if let Ok(response) = &mut result {
+                response.headers_mut().insert(X_REQUEST_ID, request_id);
+            }
+            if let Some(outcome) = access.outcome {
+                config.metrics.record_request(access.method.as_ref().map(|method| &method[..]), outcome);
+            }
+            if let Some(exporter) = &config.trace_exporter {
+                trace.set_attribute("request_id", json!(access.request_id));
+                trace.set_attribute("seq", json!(access.seq));
+                trace.set_attribute("peer", json!(access.peer.to_string()));
+                trace.set_attribute("rpc.method", json!(access.method));
+                trace.set_attribute("decision", json!(access.outcome.map(Outcome::label)));
+                exporter.export(trace);
+            }
+            if let Some(access_log) = &config.access_log {
+                access_log.write(&access);
+            }
+            for hook in &config.hooks {
+                hook(&access);
+            }
+            result
+        })
+    }
+}
+
+/// Sends the request to the upstream and returns the response as soon as its head arrives.
+async fn send(
+    config: &Config,
+    header: Parts,
+    buffer: Vec<u8>,
+    trace: &mut Trace,
+) -> Result<(ResponseParts, Body), Error> {
+    let span = trace.open("upstream", SpanKind::Client);
+    let mut req = Request::from_parts(header, Body::from(buffer));
+    *req.uri_mut() = config.forward.clone();
+    // The traceparent of the client is passed through unless the trace is exported.
+    if config.trace_exporter.is_some() {
+        let traceparent =
+            HeaderValue::from_str(&trace.traceparent(&span)).expect("A traceparent is a valid header value");
+        req.headers_mut().insert(TRACEPARENT, traceparent);
+    }
+
+    let _in_flight = config.metrics.upstream_started();
+    let started = Instant::now();
+    let response = Client::new().request(req).await;
+    config.health.set_upstream_healthy(response.is_ok());
+    let mut response = response?;
+    response.headers_mut().insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+    config.metrics.observe_upstream_latency(started.elapsed());
+    trace.close(span, vec![("http.status_code", json!(response.status().as_u16()))]);
+    let (parts, body) = response.into_parts();
+    match content_length(&parts.headers) {
+        Some(length) if length > config.max_response_size => Err(Error::ResponseTooLarge(config.max_response_size)),
+        _ => Ok((parts, body)),
+    }
+}
+
+/// Sends the request to the upstream and reads the whole response.
+async fn forward(
+    config: &Config,
+    header: Parts,
+    buffer: Vec<u8>,
+    seq: u64,
+    trace: &mut Trace,
+) -> Result<(ResponseParts, Vec<u8>), Error> {
+    let (parts, body) = send(config, header, buffer, trace).await?;
+    let buffer = collect_body_up_to(body, config.max_response_size).await?;
+    trace!("seq: {}, forward, bytes: {}", seq, String::from_utf8_lossy(&buffer));
+    Ok((parts, buffer))
+}
+
+fn content_length(headers: &HeaderMap) -> Option<usize> {
+    headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+/// Passes the body through, and aborts it once it exceeds `max_size` bytes.
+fn stream_body(body: Body, max_size: usize) -> Body {
+    let mut size = 0;
+    Body::wrap_stream(body.map_err(Error::from).and_then(move |chunk| {
+        size += chunk.len();
+        future::ready(if size > max_size {
+            Err(Error::ResponseTooLarge(max_size))
+        } else {
+            Ok(chunk)
+        })
+    }))
+}
+
+pub(crate) async fn collect_body_up_to(mut body: Body, max_size: usize) -> Result<Vec<u8>, Error> {
+    let mut buffer = Vec::new();
+    while let Some(chunk) = body.try_next().await? {
+        if buffer.len() + chunk.len() > max_size {
+            return Err(Error::ResponseTooLarge(max_size))
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+    Ok(buffer)
+}
+
+/// The request that passed the policies.
+struct Checked<'a> {
+    method: Cow<'a, str>,
+    /// `None` when only the method had to be read.
+    request: Option<Value>,
+    rewritten: bool,
+    /// Why a rule in the monitor mode would block the request.
+    would_block: Option<String>,
+}
+
+/// Asks the policies of the filter in order.
+///
+/// The whole request is parsed only when a policy, a response filter, or the options of the method need more than
+/// the method, so a large request of a method that is not in the allowed list costs no allocation.
+fn check_request<'a>(
+    config: &Config,
+    buffer: &'a [u8],
+    peer: IpAddr,
+    headers: &HeaderMap,
+    identity: Option<&str>,
+    seq: u64,
+) -> Result<Checked<'a>, Error> {
+    let allowed_list = config.policy.allowed_list();
+    let learning = config.learner.is_some();
+    // Learns the calls that the other policies let through, so that their denials are not suggested.
+    let learn = |method: &str| {
+        if let Some(learner) = &config.learner {
+            match identity {
+                Some(identity) => learner.record(identity, method),
+                None => learner.record(&peer.to_string(), method),
+            }
+        }
+    };
+    let Admitted {
+        method,
+        mut would_block,
+    } = filter_allowed_request(buffer, &allowed_list, config.monitor, learning, seq)?;
+    let needs_request = config.policies.len() > 1
+        || !config.response_filters.is_empty()
+        || allowed_list.options(&method).map(|options| options.cache.is_some() || options.coalesce).unwrap_or(false);
+    if !needs_request {
+        learn(&method);
+        return Ok(Checked {
+            method,
+            request: None,
+            rewritten: false,
+            would_block,
+        })
+    }
+
+    let mut request = serde_json::from_slice::<Value>(buffer)?;
+    let mut rewritten = false;
+    // The allowed list, which is the first policy, is already asked.
+    for policy in &config.policies[1..] {
+        let decision = policy.check(&RequestInfo {
+            method: method_of(&request)?,
+            request: &request,
+            peer,
+            headers,
+            identity,
+            tip: config.cache.tip(),
+        });
+        match decision {
+            Decision::Allow => {}
+            Decision::Deny(reason) if config.monitor => {
+                warn!("seq: {}, would block: {}", seq, reason);
+                would_block.get_or_insert(reason);
+            }
+            Decision::Deny(reason) => {
+                info!("seq: {}, blocked: {}", seq, reason);
+                return Err(Error::Denied {
+                    method: method_of(&request)?.to_string(),
+                    reason,
+                })
+            }
+            Decision::Rewrite(rewrite) => {
+                debug!("seq: {}, rewritten to {}", seq, rewrite);
+                // A rewrite can't call a method that the allowed list doesn't allow.
+                let rewritten_method = method_of(&rewrite)?;
+                if rewritten_method != method_of(&request)? {
+                    let admitted =
+                        filter_allowed_method(rewritten_method.into(), &allowed_list, config.monitor, learning, seq)?;
+                    if let Some(reason) = admitted.would_block {
+                        would_block.get_or_insert(reason);
+                    }
+                }
+                request = rewrite;
+                rewritten = true;
+            }
+        }
+    }
+    learn(&method);
+    Ok(Checked {
+        method: if rewritten {
+            Cow::Owned(method_of(&request)?.to_string())
+        } else {
+            method
+        },
+        request: Some(request),
+        rewritten,
+        would_block,
+    })
+}
+
+/// Applies the response rules of the method, then asks the response filters of the filter in order. Returns the
+/// response to send and whether it was rewritten.
+///
+/// A response that is not JSON is denied unless there is no rule or response filter.
+fn check_response(
+    config: &Config,
+    access: &mut Access,
+    trace: &mut Trace,
+    request: &RequestInfo<'_>,
+    rules: &[ResponseRule],
+    buffer: Vec<u8>,
+) -> Result<(Vec<u8>, bool), Error> {
+    if rules.is_empty() && config.response_filters.is_empty() {
+        return Ok((buffer, false))
+    }
+    let span = trace.open("filter_response", SpanKind::Internal);
+    let checked = check_response_with_filters(config, access.seq, request, rules, &buffer);
+    trace.close(span, vec![("allowed", json!(checked.is_ok()))]);
+    match checked {
+        Ok(Some(response)) => Ok((serde_json::to_vec(&response)?, true)),
+        Ok(None) => Ok((buffer, false)),
+        Err(err) => {
+            if let Error::Denied {
+                reason,
+                ..
+            } = &err
+            {
+                access.rule = Some(reason.clone());
+                access.outcome = Some(Outcome::Blocked);
+            }
+            Err(err)
+        }
     }
 }
 
-fn filter_allowed_request(buffer: &[u8], allowed_rpcs: &BisectSet<String>, seq: u64) -> Result<(), Error> {
-    let request = serde_json::from_slice::<serde_json::Value>(&buffer)?;
+/// Returns the rewritten response, if any.
+fn check_response_with_filters(
+    config: &Config,
+    seq: u64,
+    request: &RequestInfo<'_>,
+    rules: &[ResponseRule],
+    buffer: &[u8],
+) -> Result<Option<Value>, Error> {
+    let deny = |reason: String| {
+        info!("seq: {}, response blocked: {}", seq, reason);
+        Error::Denied {
+            method: request.method.to_string(),
+            reason,
+        }
+    };
+    let mut response = match serde_json::from_slice::<Value>(buffer) {
+        Ok(response) => response,
+        Err(err) => return Err(deny(format!("The response is not JSON: {}", err))),
+    };
+    let mut rewritten = !rules.is_empty();
+    redact::apply(rules, &mut response);
+    for response_filter in &config.response_filters {
+        match response_filter.filter(request, &response) {
+            Decision::Allow => {}
+            Decision::Deny(reason) => return Err(deny(reason)),
+            Decision::Rewrite(rewrite) => {
+                debug!("seq: {}, response rewritten to {}", seq, rewrite);
+                response = rewrite;
+                rewritten = true;
+            }
+        }
+    }
+    Ok(if rewritten {
+        Some(response)
+    } else {
+        None
+    })
+}
+
+fn method_of(request: &Value) -> Result<&str, Error> {
     let method = request.get("method").ok_or(Error::MethodIsNotDefined)?;
-    let method = method.as_str().ok_or(Error::MethodIsNotString)?;
+    method.as_str().ok_or(Error::MethodIsNotString)
+}
+
+/// A request that the allowed list lets through.
+pub struct Admitted<'a> {
+    pub method: Cow<'a, str>,
+    /// Why a rule in the monitor mode would block the request.
+    pub would_block: Option<String>,
+}
+
+/// Returns the method of the request if the allowed list lets it through, without parsing the rest of the request.
+///
+/// With `monitor`, every method is let through, and the ones that the allowed list would block are reported. With
+/// `learning`, every method is let through silently.
+pub fn filter_allowed_request<'a>(
+    buffer: &'a [u8],
+    allowed_list: &AllowedList,
+    monitor: bool,
+    learning: bool,
+    seq: u64,
+) -> Result<Admitted<'a>, Error> {
+    let method = envelope::method(buffer)?;
     debug!("seq: {}, method: {}", seq, method);
-    if allowed_rpcs.contains(method) {
-        Ok(())
-    } else {
-        info!("seq: {}, blocked", seq);
-        Err(Error::NotAllowedMethod(method.to_string()))
+    filter_allowed_method(method, allowed_list, monitor, learning, seq)
+}
+
+/// Returns the method if the allowed list lets it through, like `filter_allowed_request`.
+pub fn filter_allowed_method<'a>(
+    method: Cow<'a, str>,
+    allowed_list: &AllowedList,
+    monitor: bool,
+    learning: bool,
+    seq: u64,
+) -> Result<Admitted<'a>, Error> {
+    match allowed_list.denial(&method) {
+        None => Ok(Admitted {
+            method,
+            would_block: None,
+        }),
+        Some(_) if learning => Ok(Admitted {
+            method,
+            would_block: None,
+        }),
+        Some(denial) if denial.monitored || monitor => {
+            warn!("seq: {}, would block: {}", seq, denial.reason);
+            Ok(Admitted {
+                method,
+                would_block: Some(denial.reason),
+            })
+        }
+        Some(denial) => {
+            info!("seq: {}, blocked: {}", seq, denial.reason);
+            Err(Error::Denied {
+                method: method.into_owned(),
+                reason: denial.reason,
+            })
+        }
     }
 }
 
-async fn collect_body(body: Body) -> Result<Vec<u8>, hyper::Error> {
+pub async fn collect_body(body: Body) -> Result<Vec<u8>, hyper::Error> {
     body.map_ok(|bytes| bytes.to_vec()).try_concat().await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{filter_builder, post, PEER};
+    use std::convert::Infallible;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn monitor_the_denials() {
+        let would_block = Arc::new(Mutex::new(Vec::new()));
+        let accesses = Arc::clone(&would_block);
+        let maker = filter_builder("ping", || Body::from(r#"{"jsonrpc":"2.0","id":1,"result":null}"#))
+            .monitor(true)
+            .policy(|request: &RequestInfo<'_>| match request.method {
+                "ping" => Decision::Deny("no ping".to_string()),
+                _ => Decision::Allow,
+            })
+            .on_access(move |access| accesses.lock().unwrap().push(access.would_block.clone()))
+            .build();
+        let mut filter = maker.filter(PEER);
+
+        assert!(filter.call(post(r#"{"jsonrpc":"2.0","id":1,"method":"ping"}"#)).await.is_ok());
+        assert!(filter.call(post(r#"{"jsonrpc":"2.0","id":1,"method":"add"}"#)).await.is_ok());
+        assert_eq!(
+            vec![Some("no ping".to_string()), Some("add is not in the allowed list".to_string())],
+            *would_block.lock().unwrap()
+        );
+        let metrics = maker.config().metrics.render(&maker.config().cache);
+        assert!(metrics.contains("jsonrpc_filter_would_block_total{method=\"ping\"} 1\n"));
+        assert!(metrics.contains("jsonrpc_filter_would_block_total{method=\"(other)\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn abort_large_responses() {
+        let ping = r#"{"jsonrpc":"2.0","id":1,"method":"ping"}"#;
+        let maker = filter_builder("ping", || Body::from(vec![b' '; 1000])).max_response_size(100).build();
+        match maker.filter(PEER).call(post(ping)).await {
+            Err(Error::ResponseTooLarge(100)) => {}
+            _ => panic!("A response with a large content length must be aborted"),
+        }
+
+        let chunked = || Body::wrap_stream(futures::stream::iter(vec![Ok::<_, Infallible>(vec![b' '; 60]); 2]));
+        let maker = filter_builder("ping", chunked).max_response_size(100).build();
+        let response = maker.filter(PEER).call(post(ping)).await.unwrap();
+        assert!(collect_body(response.into_body()).await.is_err(), "A large streamed response must be aborted");
+
+        let maker = filter_builder("ping cache=60", chunked).max_response_size(100).build();
+        match maker.filter(PEER).call(post(ping)).await {
+            Err(Error::ResponseTooLarge(100)) => {}
+            _ => panic!("A large buffered response must be aborted"),
+        }
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::Error;
+use crate::config::Config;
+use crate::filter::collect_body;
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
+use log::{debug, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const PING_REQUEST: &str = r#"{"jsonrpc":"2.0","id":0,"method":"ping","params":[]}"#;
+
+/// Whether the filter can serve requests.
+///
+/// The upstream is healthy once it answers a health check or a forwarded request, and unhealthy when it can't be
+/// reached.
+#[derive(Default)]
+pub struct Health {
+    upstream_healthy: AtomicBool,
+    shutting_down: AtomicBool,
+}
+
+impl Health {
+    /// Reports not ready from now on, so that the load balancers stop sending new requests.
+    pub fn start_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_upstream_healthy(&self, healthy: bool) {
+        if self.upstream_healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            if healthy {
+                debug!("The upstream is healthy");
+            } else {
+                warn!("The upstream is unhealthy");
+            }
+        }
+    }
+
+    /// Returns the reason when the filter is not ready.
+    pub fn readiness(&self) -> Result<(), &'static str> {
+        if self.shutting_down.load(Ordering::Relaxed) {
+            return Err("The filter is shutting down")
+        }
+        if !self.upstream_healthy.load(Ordering::Relaxed) {
+            return Err("The upstream is unhealthy")
+        }
+        Ok(())
+    }
+}
+
+/// Answers `GET /healthz` and `GET /readyz`. Other requests are left to the filter.
+pub fn probe(health: &Health, req: &Request<Body>) -> Option<Result<Response<Body>, Error>> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return None
+    }
+    let result = match req.uri().path() {
+        "/healthz" => Ok(()),
+        "/readyz" => health.readiness(),
+        _ => return None,
+    };
+    let (status, body) = match result {
+        Ok(()) => (StatusCode::OK, "OK"),
+        Err(reason) => (StatusCode::SERVICE_UNAVAILABLE, reason),
+    };
+    Some(
+        Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, HeaderValue::from_static("text/plain"))
+            .body(Body::from(body))
+            .map_err(Error::from),
+    )
+}
+
+/// Pings the upstream periodically.
+pub async fn watch(config: Arc<Config>, interval: Duration) {
+    let client = Client::new();
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let result = ping(&client, &config.forward).await;
+        if let Err(err) = &result {
+            debug!("Cannot ping the upstream: {}", err);
+        }
+        config.health.set_upstream_healthy(result.is_ok());
+    }
+}
+
+async fn ping(client: &Client<HttpConnector>, forward: &hyper::Uri) -> Result<(), Error> {
+    let request = Request::post(forward.clone())
+        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+        .body(Body::from(PING_REQUEST))?;
+    let response = client.request(request).await?;
+    let status = response.status();
+    let buffer = collect_body(response.into_body()).await?;
+    if !status.is_success() {
+        return Err(Error::UnexpectedResponse(format!("{} {}", status, String::from_utf8_lossy(&buffer))))
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(path: &str) -> Request<Body> {
+        Request::get(path).body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn ready_when_the_upstream_is_healthy() {
+        let health = Health::default();
+        assert_eq!(StatusCode::OK, probe(&health, &get("/healthz")).unwrap().unwrap().status());
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, probe(&health, &get("/readyz")).unwrap().unwrap().status());
+        health.set_upstream_healthy(true);
+        assert_eq!(StatusCode::OK, probe(&health, &get("/readyz")).unwrap().unwrap().status());
+        health.start_shutdown();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, probe(&health, &get("/readyz")).unwrap().unwrap().status());
+        assert_eq!(StatusCode::OK, probe(&health, &get("/healthz")).unwrap().unwrap().status());
+        assert!(probe(&health, &get("/")).is_none());
+        assert!(probe(&health, &Request::post("/healthz").body(Body::empty()).unwrap()).is_none());
+    }
+}
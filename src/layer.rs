@@ -0,0 +1,160 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::Error;
+use crate::filter::{collect_body, filter_allowed_request, DynFuture};
+use crate::policy::AllowedListPolicy;
+use hyper::service::Service;
+use hyper::{Body, Request};
+use std::error::Error as StdError;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+
+pub type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// Lets only the allowed JSON-RPC calls through to the wrapped service.
+///
+/// Every request must be a JSON-RPC call of an allowed method. The others fail with an `Error`, boxed as tower's
+/// middlewares do, without reaching the wrapped service.
+#[derive(Clone)]
+pub struct JsonRpcFilterLayer {
+    policy: Arc<AllowedListPolicy>,
+    counter: Arc<AtomicU64>,
+    monitor: bool,
+}
+
+impl JsonRpcFilterLayer {
+    pub fn new(policy: Arc<AllowedListPolicy>) -> Self {
+        JsonRpcFilterLayer {
+            policy,
+            counter: Arc::new(AtomicU64::new(0)),
+            monitor: false,
+        }
+    }
+
+    /// Lets every JSON-RPC call through, and logs the ones that the allowed list would block.
+    pub fn monitor(mut self, monitor: bool) -> Self {
+        self.monitor = monitor;
+        self
+    }
+}
+
+impl<S> Layer<S> for JsonRpcFilterLayer {
+    type Service = JsonRpcFilter<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        JsonRpcFilter {
+            inner,
+            policy: Arc::clone(&self.policy),
+            counter: Arc::clone(&self.counter),
+            monitor: self.monitor,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct JsonRpcFilter<S> {
+    inner: S,
+    policy: Arc<AllowedListPolicy>,
+    counter: Arc<AtomicU64>,
+    monitor: bool,
+}
+
+impl<S> Service<Request<Body>> for JsonRpcFilter<S>
+where
+    S: Service<Request<Body>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = DynFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        // The service that became ready is the one to call.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let policy = Arc::clone(&self.policy);
+        let seq = self.counter.fetch_add(1, Ordering::SeqCst);
+        let monitor = self.monitor;
+        Box::pin(async move {
+            let (parts, body) = req.into_parts();
+            let buffer = collect_body(body).await.map_err(Error::from)?;
+            filter_allowed_request(&buffer, &policy.allowed_list(), monitor, false, seq)?;
+            inner.call(Request::from_parts(parts, Body::from(buffer))).await.map_err(Into::into)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{policy, post};
+    use futures::future;
+    use hyper::Response;
+
+    /// Responds with the body of the request.
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Service<Request<Body>> for Echo {
+        type Response = Response<Body>;
+        type Error = hyper::Error;
+        type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request<Body>) -> Self::Future {
+            future::ok(Response::new(req.into_body()))
+        }
+    }
+
+    #[tokio::test]
+    async fn filter_the_wrapped_service() {
+        let mut filter = JsonRpcFilterLayer::new(Arc::new(policy("ping"))).layer(Echo);
+
+        let ping = r#"{"jsonrpc":"2.0","id":1,"method":"ping"}"#;
+        let response = filter.call(post(ping)).await.unwrap();
+        assert_eq!(ping.as_bytes(), &collect_body(response.into_body()).await.unwrap()[..]);
+
+        let err = filter.call(post(r#"{"jsonrpc":"2.0","id":1,"method":"add"}"#)).await.unwrap_err();
+        match err.downcast_ref::<Error>() {
+            Some(Error::Denied {
+                method,
+                reason,
+            }) => assert_eq!(("add", "add is not in the allowed list"), (&method[..], &reason[..])),
+            _ => panic!("The method must be blocked: {}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn monitor_the_wrapped_service() {
+        let mut filter = JsonRpcFilterLayer::new(Arc::new(policy("ping"))).monitor(true).layer(Echo);
+
+        let add = r#"{"jsonrpc":"2.0","id":1,"method":"add"}"#;
+        let response = filter.call(post(add)).await.unwrap();
+        assert_eq!(add.as_bytes(), &collect_body(response.into_body()).await.unwrap()[..]);
+        assert!(filter.call(post("{}")).await.is_err());
+    }
+}
@@ -0,0 +1,233 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Learns the methods that the clients call, to suggest an allowed list.
+
+use super::Error;
+use log::warn;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The maximum number of client groups. The calls of the groups after it are counted in `OTHER_GROUP`.
+const MAX_GROUPS: usize = 1_000;
+/// The maximum number of methods of a group. The calls of the methods after it are not counted.
+const MAX_METHODS: usize = 1_000;
+/// The group of the clients that came after `MAX_GROUPS` groups.
+const OTHER_GROUP: &str = "(other)";
+
+/// The number of calls of each method by each client group.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Learned {
+    pub groups: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+impl Learned {
+    /// Reads the file written by `Learner::save`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let learned = serde_json::from_slice::<Value>(&fs::read(path)?)?;
+        let invalid = || Error::from(io::Error::new(io::ErrorKind::InvalidData, "Invalid learned methods"));
+        let mut groups = BTreeMap::new();
+        for (group, methods) in learned.get("groups").and_then(Value::as_object).ok_or_else(invalid)? {
+            let mut counts = BTreeMap::new();
+            for (method, count) in methods.as_object().ok_or_else(invalid)? {
+                counts.insert(method.clone(), count.as_u64().ok_or_else(invalid)?);
+            }
+            groups.insert(group.clone(), counts);
+        }
+        Ok(Learned {
+            groups,
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({ "groups": self.groups })
+    }
+
+    /// Writes an allowed list of the methods called at least `min_count` times by the given groups, or by every group
+    /// if none is given. A comment with the calls comes before each method.
+    ///
+    /// A method that can't be written in the allowed list, e.g. one with whitespace, is left out.
+    pub fn suggest(&self, groups: &[&str], min_count: u64) -> String {
+        let mut methods: BTreeMap<&str, Vec<(&str, u64)>> = BTreeMap::new();
+        for (group, counts) in &self.groups {
+            if !groups.is_empty() && !groups.contains(&group.as_str()) {
+                continue
+            }
+            for (method, count) in counts {
+                methods.entry(method).or_default().push((group, *count));
+            }
+        }
+        let mut suggested = String::new();
+        for (method, calls) in methods {
+            let total: u64 = calls.iter().map(|(_, count)| count).sum();
+            if total < min_count || !can_be_listed(method) {
+                continue
+            }
+            let calls: Vec<_> = calls.iter().map(|(group, count)| format!("{}={}", group, count)).collect();
+            writeln!(suggested, "# {} calls: {}", total, calls.join(" ")).unwrap();
+            writeln!(suggested, "{}", method).unwrap();
+        }
+        suggested
+    }
+}
+
+fn can_be_listed(method: &str) -> bool {
+    !method.is_empty() && !method.starts_with('!') && !method.starts_with('#') && !method.contains(char::is_whitespace)
+}
+
+/// Counts the methods while the filter runs in the learn mode.
+///
+/// The groups and the methods are capped, so that clients calling random methods can't exhaust the memory.
+#[derive(Default)]
+pub struct Learner {
+    learned: Mutex<Learned>,
+    /// Whether a method has been left out by the cap, which is warned once.
+    capped: AtomicBool,
+}
+
+impl Learner {
+    pub fn record(&self, group: &str, method: &str) {
+        let mut learned = self.learned.lock().unwrap();
+        let group = if learned.groups.len() < MAX_GROUPS || learned.groups.contains_key(group) {
+            group
+        } else {
+            OTHER_GROUP
+        };
+        let counts = learned.groups.entry(group.to_string()).or_default();
+        if let Some(count) = counts.get_mut(method) {
+            *count += 1;
+        } else if counts.len() < MAX_METHODS {
+            counts.insert(method.to_string(), 1);
+        } else if !self.capped.swap(true, Ordering::Relaxed) {
+            warn!("{} has called more than {} methods, so the new methods are not learned", group, MAX_METHODS);
+        }
+    }
+
+    pub fn learned(&self) -> Learned {
+        self.learned.lock().unwrap().clone()
+    }
+
+    /// Writes what is learned as JSON, replacing the file at once.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut temporary = path.to_path_buf().into_os_string();
+        temporary.push(".tmp");
+        fs::write(&temporary, self.learned().to_json().to_string())?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+}
+
+/// Saves what is learned every `interval`.
+pub async fn save_every(learner: Arc<Learner>, path: PathBuf, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        if let Err(err) = learner.save(&path) {
+            warn!("Cannot save the learned methods: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allowed_list::AllowedList;
+    use crate::policy::{Decision, RequestInfo};
+    use crate::test_util::{filter_builder, post, PEER};
+    use hyper::header::HeaderName;
+    use hyper::service::Service;
+    use hyper::{Body, Request};
+
+    #[test]
+    fn suggest_an_allowed_list() {
+        let learner = Learner::default();
+        for _ in 0..3 {
+            learner.record("alice", "chain_getSeq");
+        }
+        learner.record("alice", "ping");
+        learner.record("10.0.0.1", "chain_getSeq");
+        learner.record("10.0.0.1", "not a method");
+
+        let path = std::env::temp_dir().join(format!("jsonrpc-filter-learned-{}.json", std::process::id()));
+        learner.save(&path).unwrap();
+        let learned = Learned::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(learner.learned(), learned);
+
+        let suggested = learned.suggest(&[], 1);
+        assert_eq!("# 4 calls: 10.0.0.1=1 alice=3\nchain_getSeq\n# 1 calls: alice=1\nping\n", suggested);
+        let allowed_list = AllowedList::parse(suggested.as_bytes()).unwrap();
+        assert_eq!(vec!["chain_getSeq", "ping"], allowed_list.methods().collect::<Vec<_>>());
+
+        assert_eq!("# 4 calls: 10.0.0.1=1 alice=3\nchain_getSeq\n", learned.suggest(&[], 2));
+        assert_eq!("# 1 calls: 10.0.0.1=1\nchain_getSeq\n", learned.suggest(&["10.0.0.1"], 1));
+    }
+
+    #[test]
+    fn cap_the_groups_and_the_methods() {
+        let learner = Learner::default();
+        for group in 0..=MAX_GROUPS {
+            learner.record(&group.to_string(), "ping");
+        }
+        learner.record("0", "ping");
+        for method in 0..=MAX_METHODS {
+            learner.record("alice", &method.to_string());
+        }
+        let learned = learner.learned();
+        assert_eq!(MAX_GROUPS + 1, learned.groups.len());
+        assert_eq!(Some(&2), learned.groups["0"].get("ping"));
+        assert_eq!(Some(&1), learned.groups[OTHER_GROUP].get("ping"));
+        assert_eq!(MAX_METHODS, learned.groups[OTHER_GROUP].len());
+        assert_eq!(None, learned.groups[OTHER_GROUP].get(&MAX_METHODS.to_string()));
+    }
+
+    #[tokio::test]
+    async fn learn_the_methods() {
+        let learner = Arc::new(Learner::default());
+        let maker = filter_builder("ping", || Body::from(r#"{"jsonrpc":"2.0","id":1,"result":null}"#))
+            .identity_header(HeaderName::from_static("x-user"))
+            .learner(Arc::clone(&learner))
+            .policy(|request: &RequestInfo<'_>| match request.method {
+                "personal_sign" => Decision::Deny("personal".to_string()),
+                _ => Decision::Allow,
+            })
+            .build();
+        let mut filter = maker.filter(PEER);
+
+        let send = r#"{"jsonrpc":"2.0","id":1,"method":"mempool_sendSignedTransaction"}"#;
+        let request = Request::post("/").header("x-user", "alice").body(Body::from(send)).unwrap();
+        assert!(filter.call(request).await.is_ok(), "A method out of the allowed list must be forwarded");
+        assert!(filter.call(post(r#"{"jsonrpc":"2.0","id":1,"method":"ping"}"#)).await.is_ok());
+        match filter.call(post(r#"{"jsonrpc":"2.0","id":1,"method":"personal_sign"}"#)).await {
+            Err(Error::Denied {
+                reason,
+                ..
+            }) => assert_eq!("personal", reason),
+            _ => panic!("The other policies must be asked"),
+        }
+        assert_eq!(
+            "# 1 calls: alice=1\nmempool_sendSignedTransaction\n# 1 calls: 127.0.0.1=1\nping\n",
+            learner.learned().suggest(&[], 1)
+        );
+    }
+}
@@ -1 +1,39 @@
+extern crate serde_crate as serde;
+
+pub mod access_log;
+pub mod admin;
+pub mod allowed_list;
 pub mod bisect_set;
+pub mod cache;
+pub mod coalesce;
+pub mod config;
+pub mod discover;
+pub mod envelope;
+pub mod error;
+pub mod filter;
+pub mod health;
+pub mod layer;
+pub mod learn;
+pub mod lint;
+pub mod method_set;
+pub mod metrics;
+pub mod perfect_hash;
+#[cfg(feature = "plugin")]
+pub mod plugin;
+pub mod policy;
+pub mod rate_limit;
+pub mod record;
+pub mod redact;
+#[cfg(feature = "script")]
+pub mod script;
+pub mod shadow;
+#[cfg(test)]
+mod test_util;
+pub mod tip;
+pub mod trace;
+
+pub use self::config::{Config, FilterBuilder, ServiceMaker};
+pub use self::error::Error;
+pub use self::filter::Filter;
+pub use self::layer::JsonRpcFilterLayer;
+pub use self::policy::{AllowedListPolicy, Decision, Policy, RequestInfo, ResponseFilter};
@@ -0,0 +1,164 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Checks an allowed list for mistakes that loading it accepts.
+
+use super::Error;
+use crate::allowed_list::{denies, parse_directive, prefix, Entry, MethodOptions};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::BufRead;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Finding {
+    pub line: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: {}: {}", self.line, severity, self.message)
+    }
+}
+
+/// Returns the findings in the order of the lines.
+pub fn check<R: BufRead>(reader: R) -> Result<Vec<Finding>, Error> {
+    let mut findings = Vec::new();
+    // The first line of each method and pattern.
+    let mut allowed: Vec<(&str, usize)> = Vec::new();
+    let mut denied: Vec<(&str, usize, bool)> = Vec::new();
+    let lines = reader.lines().collect::<Result<Vec<_>, _>>()?;
+    for (index, line) in lines.iter().enumerate() {
+        let number = index + 1;
+        let mut report = |severity, message| {
+            findings.push(Finding {
+                line: number,
+                severity,
+                message,
+            })
+        };
+        if line.trim_end() != line && !line.trim().is_empty() {
+            report(Severity::Warning, "Trailing whitespace".to_string());
+        }
+        match Entry::parse(line) {
+            Entry::Empty => report(Severity::Warning, "Empty entry".to_string()),
+            Entry::Comment => {}
+            Entry::Directive(name, rest) => {
+                if let Err(reason) = parse_directive(name, rest) {
+                    report(Severity::Error, reason);
+                }
+            }
+            Entry::Deny(pattern, rest) => {
+                let name = prefix(pattern).unwrap_or(pattern);
+                if name.is_empty() {
+                    report(Severity::Error, format!("!{} denies every method", pattern));
+                }
+                if let Some(invalid) = name.chars().find(|c| !is_valid(*c)) {
+                    report(Severity::Error, format!("Invalid character {:?} in !{}", invalid, pattern));
+                }
+                let monitor = rest.trim() == "monitor";
+                if !monitor && !rest.trim().is_empty() {
+                    report(Severity::Error, format!("A deny of {} can only have the monitor option", pattern));
+                }
+                match denied.iter().find(|(previous, ..)| *previous == pattern) {
+                    Some((_, first, _)) => {
+                        report(Severity::Warning, format!("!{} is a duplicate of line {}", pattern, first))
+                    }
+                    None => denied.push((pattern, number, monitor)),
+                }
+            }
+            Entry::Allow(method, rest) => {
+                if let Some(invalid) = method.chars().find(|c| !is_valid(*c)) {
+                    report(Severity::Error, format!("Invalid character {:?} in {}", invalid, method));
+                }
+                if let Err(reason) = MethodOptions::parse(rest) {
+                    report(Severity::Error, reason);
+                }
+                match allowed.iter().find(|(previous, _)| *previous == method) {
+                    Some((_, first)) => report(Severity::Error, format!("{} is a duplicate of line {}", method, first)),
+                    None => allowed.push((method, number)),
+                }
+            }
+        }
+    }
+
+    for (method, line) in allowed {
+        // A deny in the monitor mode doesn't block.
+        let shadowing = denied.iter().find(|(pattern, _, monitor)| !monitor && denies(pattern, method));
+        if let Some((pattern, deny_line, _)) = shadowing {
+            findings.push(Finding {
+                line,
+                severity: Severity::Error,
+                message: format!("{} is never allowed because !{} at line {} denies it", method, pattern, deny_line),
+            });
+        }
+    }
+    findings.sort_by_key(|finding| finding.line);
+    Ok(findings)
+}
+
+/// The characters of the methods of CodeChain and the usual JSON-RPC servers.
+fn is_valid(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lint(file: &str) -> Vec<String> {
+        check(file.as_bytes()).unwrap().iter().map(ToString::to_string).collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn accept_a_clean_list() {
+        assert_eq!(
+            Vec::<String>::new(),
+            lint("# Public\n!personal_*\n!chain_* monitor\nchain_getSeq cache=tip\nping\n")
+        );
+        assert_eq!(Vec::<String>::new(), lint(include_str!("../allowed.txt")));
+        assert_eq!(vec!["1: error: !* denies every method"], lint("!*\n"));
+    }
+
+    #[test]
+    fn report_every_mistake() {
+        let file =
+            "ping \n\nchain_getSeq\nchain_getSeq cache=1\nchain_get$eq\nversion ttl=1\n!personal_*\npersonal_sign\n\
+                    @method-set fst\n";
+        assert_eq!(
+            vec![
+                "1: warning: Trailing whitespace",
+                "2: warning: Empty entry",
+                "4: error: chain_getSeq is a duplicate of line 3",
+                "5: error: Invalid character '$' in chain_get$eq",
+                "6: error: Unknown option ttl=1",
+                "8: error: personal_sign is never allowed because !personal_* at line 7 denies it",
+                "9: error: Unknown method set fst",
+            ],
+            lint(file)
+        );
+    }
+}
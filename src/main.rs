@@ -14,25 +14,38 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-mod bisect_set;
-mod config;
-mod error;
-mod filter;
-
-use self::bisect_set::BisectSet;
-use self::config::Config;
-use self::error::Error;
-use self::filter::Filter;
-use crate::config::ServiceMaker;
 use clap::{load_yaml, value_t_or_exit};
+use futures::channel::oneshot;
+use futures::future::{self, Either};
 use futures::TryFutureExt;
+use hyper::header::HeaderName;
+use hyper::server::conn::AddrIncoming;
+use hyper::server::Builder;
 use hyper::Server;
-use log::info;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::iter::FromIterator;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use jsonrpc_filter::access_log::{AccessLog, Output, Rotation};
+use jsonrpc_filter::admin::{AdminMaker, AuditLog, PolicyApi};
+use jsonrpc_filter::allowed_list::AllowedList;
+use jsonrpc_filter::discover;
+use jsonrpc_filter::learn::{self, Learned, Learner};
+use jsonrpc_filter::lint::{self, Severity};
+use jsonrpc_filter::record::{self, Recorder, Summary};
+use jsonrpc_filter::redact::JsonPath;
+use jsonrpc_filter::shadow::Shadow;
+use jsonrpc_filter::{health, tip, trace, AllowedListPolicy, FilterBuilder};
+use log::{error, info, warn};
+use std::fs;
+use std::io::{self, BufReader};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::process;
 use std::sync::Arc;
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinError;
+
+/// How often the learned methods are written in the learn mode.
+const LEARN_SAVE_INTERVAL: Duration = Duration::from_secs(10);
 
 #[tokio::main]
 async fn main() {
@@ -42,22 +55,368 @@ async fn main() {
 
     let yaml = load_yaml!("clap.yml");
     let args = clap::App::from_yaml(yaml).version(VERSION).get_matches();
+    if let Some(args) = args.subcommand_matches("check") {
+        process::exit(check(args.value_of("allowed_list").unwrap()));
+    }
+    if let Some(args) = args.subcommand_matches("suggest") {
+        process::exit(suggest(args));
+    }
+    if let Some(args) = args.subcommand_matches("diff-upstream") {
+        process::exit(diff_upstream(args).await);
+    }
+    if let Some(args) = args.subcommand_matches("replay") {
+        process::exit(replay(args).await);
+    }
     let bind = value_t_or_exit!(args.value_of("bind"), Ipv4Addr);
     let port = value_t_or_exit!(args, "port", u16);
     let forward: hyper::Uri = value_t_or_exit!(args, "forward", String).parse().unwrap();
-    let allowed_list = args.value_of("allowed_list").unwrap();
-    let allowed_rpcs = BufReader::new(File::open(allowed_list).unwrap())
-        .lines()
-        .map(|line| line.map(|line| line.trim().to_string()))
-        .collect::<Result<Vec<_>, _>>()
-        .map(BisectSet::from_iter)
-        .unwrap();
+    let policy = AllowedListPolicy::load(args.value_of("allowed_list").unwrap().into()).unwrap();
+    let cache_size = value_t_or_exit!(args, "cache_size", usize);
+    let max_response_size = value_t_or_exit!(args, "max_response_size", usize);
+    let tip_interval = interval(&args, "tip_interval");
+    let health_interval = interval(&args, "health_interval");
+    let access_log = args.value_of("access_log").map(|path| {
+        let output = if path == "-" {
+            Output::Stdout
+        } else {
+            Output::File {
+                path: path.into(),
+                rotation: rotation(&args, "access_log_max_size", "access_log_max_age"),
+            }
+        };
+        open_or_exit(path, AccessLog::new(output))
+    });
+    let trace_exporter = match (args.value_of("trace_file"), args.value_of("otlp_endpoint")) {
+        (Some(path), _) => Some((path, trace::Output::File {
+            path: path.into(),
+            rotation: rotation(&args, "trace_file_max_size", "trace_file_max_age"),
+        })),
+        (None, Some(endpoint)) => {
+            Some((endpoint, trace::Output::Collector(value_t_or_exit!(args, "otlp_endpoint", hyper::Uri))))
+        }
+        (None, None) => None,
+    }
+    .map(|(target, output)| open_or_exit(target, trace::Exporter::new(output)));
+    let admin_bind = value_t_or_exit!(args.value_of("admin_bind"), Ipv4Addr);
+    let admin_port = if args.is_present("admin_port") {
+        Some(value_t_or_exit!(args, "admin_port", u16))
+    } else {
+        None
+    };
+
+    let admin_token = args.value_of("admin_token_file").map(|path| {
+        let token = fs::read_to_string(path).unwrap_or_else(|err| {
+            error!("Cannot read the admin token file {}: {}", path, err);
+            process::exit(1)
+        });
+        if token.trim().is_empty() {
+            error!("The admin token file {} is empty", path);
+            process::exit(1)
+        }
+        token.trim().to_string()
+    });
+    let audit_log = args.value_of("audit_log").map(|path| {
+        open_or_exit(
+            path,
+            AuditLog::new(Output::File {
+                path: path.into(),
+                rotation: Rotation::default(),
+            }),
+        )
+    });
+
+    let shutdown_timeout = Duration::from_secs(value_t_or_exit!(args, "shutdown_timeout", u64));
 
     let bind_addr = SocketAddrV4::new(bind, port).into();
 
-    let config = Arc::new(Config::new(forward.clone(), allowed_rpcs));
-    let server = Server::bind(&bind_addr).serve(ServiceMaker::new(config)).map_err(|e| println!("{:?}", e));
+    let mut builder = FilterBuilder::new(forward.clone(), policy)
+        .cache_size(cache_size)
+        .max_response_size(max_response_size)
+        .monitor(args.is_present("monitor"));
+    if args.is_present("rate_limit") {
+        let per_second = value_t_or_exit!(args, "rate_limit", u32);
+        if per_second == 0 {
+            clap::Error::value_validation_auto("The rate-limit must be positive".to_string()).exit();
+        }
+        builder = builder.rate_limit(per_second);
+    }
+    if args.is_present("identity_header") {
+        builder = builder.identity_header(value_t_or_exit!(args, "identity_header", HeaderName));
+    }
+    if let Some(path) = args.value_of("script") {
+        #[cfg(feature = "script")]
+        {
+            let limits = jsonrpc_filter::script::Limits {
+                max_operations: value_t_or_exit!(args, "script_max_operations", u64),
+                timeout: Duration::from_millis(value_t_or_exit!(args, "script_timeout", u64)),
+            };
+            builder = builder.policy(jsonrpc_filter::script::ScriptPolicy::load(path.into(), limits).unwrap());
+        }
+        #[cfg(not(feature = "script"))]
+        clap::Error::with_description(
+            &format!("Cannot run {} without the script feature", path),
+            clap::ErrorKind::InvalidValue,
+        )
+        .exit();
+    }
+    if let Some(path) = args.value_of("plugin") {
+        #[cfg(feature = "plugin")]
+        {
+            let limits = jsonrpc_filter::plugin::Limits {
+                fuel: value_t_or_exit!(args, "plugin_fuel", u64),
+                timeout: Duration::from_millis(value_t_or_exit!(args, "plugin_timeout", u64)),
+                ..Default::default()
+            };
+            builder = builder.plugin(jsonrpc_filter::plugin::WasmPlugin::load(path.into(), limits).unwrap());
+        }
+        #[cfg(not(feature = "plugin"))]
+        clap::Error::with_description(
+            &format!("Cannot run {} without the plugin feature", path),
+            clap::ErrorKind::InvalidValue,
+        )
+        .exit();
+    }
+    if let Some(access_log) = access_log {
+        builder = builder.access_log(access_log);
+    }
+    if let Some(trace_exporter) = trace_exporter {
+        builder = builder.trace_exporter(trace_exporter);
+    }
+    if args.is_present("shadow") {
+        let shadow = value_t_or_exit!(args, "shadow", hyper::Uri);
+        let percentage = value_t_or_exit!(args, "shadow_percentage", f64);
+        if !(0.0..=100.0).contains(&percentage) {
+            clap::Error::value_validation_auto(format!("The shadow percentage {} isn't in 0..=100", percentage)).exit();
+        }
+        let mut shadow = Shadow::new(shadow, percentage).compare(args.is_present("shadow_compare"));
+        if let Some(patterns) = args.values_of("shadow_exclude") {
+            shadow = shadow.exclude(patterns.map(ToString::to_string));
+        }
+        builder = builder.shadow(shadow);
+    }
+    if let Some(path) = args.value_of("record") {
+        let mask = args.values_of("record_mask").map(|paths| paths.map(JsonPath::parse).collect()).unwrap_or_default();
+        let output = Output::File {
+            path: path.into(),
+            rotation: Rotation::default(),
+        };
+        builder = builder.recorder(open_or_exit(path, Recorder::new(output, mask)));
+    }
+    let learner = args.value_of("learn").map(|path| {
+        let learner = Arc::new(Learner::default());
+        tokio::spawn(learn::save_every(Arc::clone(&learner), path.into(), LEARN_SAVE_INTERVAL));
+        (learner, PathBuf::from(path))
+    });
+    if args.is_present("monitor") {
+        warn!("The denied requests are forwarded in the monitor mode");
+    }
+    if let Some((learner, _)) = &learner {
+        warn!("Every method is allowed in the learn mode");
+        builder = builder.learner(Arc::clone(learner));
+    }
+    let service_maker = builder.build();
+    let config = Arc::clone(service_maker.config());
+    tokio::spawn(tip::watch(Arc::clone(&config), tip_interval));
+    tokio::spawn(health::watch(Arc::clone(&config), health_interval));
+    if let Some(admin_port) = admin_port {
+        let admin_addr = SocketAddrV4::new(admin_bind, admin_port).into();
+        let admin = try_bind(&admin_addr)
+            .serve(AdminMaker::new(Arc::clone(&config), PolicyApi::new(admin_token, audit_log)))
+            .unwrap_or_else(|err| {
+                error!("The admin server failed: {}", err);
+                process::exit(1)
+            });
+        info!("Start the admin server. bind: {}", admin_addr);
+        tokio::spawn(admin);
+    }
+    let (shutdown, shutdown_requested) = oneshot::channel::<()>();
+    let server = try_bind(&bind_addr)
+        .serve(service_maker)
+        .with_graceful_shutdown(async {
+            let _ = shutdown_requested.await;
+        })
+        .map_err(|err| error!("The server failed: {}", err));
 
     info!("Start jsonrpc-filter. bind: {}, forward: {}", bind_addr, forward);
-    server.await.unwrap();
+    let server = match future::select(tokio::spawn(server), Box::pin(termination())).await {
+        Either::Left((result, _)) => return exit_on_failure(result),
+        Either::Right(((), server)) => server,
+    };
+
+    info!("Stop accepting connections and wait for the requests in flight up to {:?}", shutdown_timeout);
+    config.health.start_shutdown();
+    let _ = shutdown.send(());
+    let finished = tokio::time::timeout(shutdown_timeout, server).await;
+    if let Some((learner, path)) = &learner {
+        if let Err(err) = learner.save(path) {
+            warn!("Cannot save the learned methods: {}", err);
+        }
+    }
+    match finished {
+        Ok(result) => {
+            exit_on_failure(result);
+            info!("All requests are finished");
+        }
+        Err(_) => {
+            warn!("Exit with {} requests in flight", config.metrics.in_flight_requests());
+            process::exit(1);
+        }
+    }
+}
+
+/// Reads the rotation of a file, where 0 disables the limit.
+fn rotation(args: &clap::ArgMatches<'_>, max_size: &str, max_age: &str) -> Rotation {
+    Rotation {
+        max_size: Some(value_t_or_exit!(args, max_size, u64)).filter(|size| *size != 0),
+        max_age: Some(value_t_or_exit!(args, max_age, u64)).filter(|seconds| *seconds != 0).map(Duration::from_secs),
+    }
+}
+
+/// Reads an interval in milliseconds, which tokio can't tick when it's 0.
+fn interval(args: &clap::ArgMatches<'_>, name: &str) -> Duration {
+    let millis = value_t_or_exit!(args, name, u64);
+    if millis == 0 {
+        clap::Error::value_validation_auto(format!("The {} must be positive", name.replace('_', "-"))).exit();
+    }
+    Duration::from_millis(millis)
+}
+
+/// Exits with 1 if the output can't be opened.
+fn open_or_exit<T>(path: &str, result: io::Result<T>) -> T {
+    result.unwrap_or_else(|err| {
+        error!("Cannot open {}: {}", path, err);
+        process::exit(1)
+    })
+}
+
+fn try_bind(addr: &SocketAddr) -> Builder<AddrIncoming> {
+    Server::try_bind(addr).unwrap_or_else(|err| {
+        error!("Cannot bind {}: {}", addr, err);
+        process::exit(1)
+    })
+}
+
+/// Exits with 1 if the server, whose error is already logged, failed.
+fn exit_on_failure(result: Result<Result<(), ()>, JoinError>) {
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(())) => process::exit(1),
+        Err(err) => {
+            error!("The server stopped: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+/// Prints the findings of the allowed list and returns the exit code.
+fn check(path: &str) -> i32 {
+    let findings = match fs::File::open(path).map_err(Into::into).and_then(|file| lint::check(BufReader::new(file))) {
+        Ok(findings) => findings,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            return 1
+        }
+    };
+    for finding in &findings {
+        println!("{}:{}", path, finding);
+    }
+    if findings.iter().any(|finding| finding.severity == Severity::Error) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Prints the suggested allowed list and returns the exit code.
+fn suggest(args: &clap::ArgMatches<'_>) -> i32 {
+    let path = args.value_of("learned").unwrap();
+    match Learned::load(path) {
+        Ok(learned) => {
+            let groups: Vec<_> = args.values_of("group").map(Iterator::collect).unwrap_or_default();
+            print!("{}", learned.suggest(&groups, value_t_or_exit!(args, "min_count", u64)));
+            0
+        }
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            1
+        }
+    }
+}
+
+/// Prints the differences between the allowed list and the upstream, and returns 0 if there's none, 1 if there are
+/// some, or 2 on an error, like `diff`.
+async fn diff_upstream(args: &clap::ArgMatches<'_>) -> i32 {
+    let upstream = match args.value_of("manifest") {
+        Some(path) => fs::read(path).map_err(Into::into).and_then(|buffer| discover::parse_manifest(&buffer)),
+        None => discover::methods(&value_t_or_exit!(args, "forward", hyper::Uri)).await,
+    };
+    let result = upstream.and_then(|upstream| {
+        let allowed_list = AllowedList::load(args.value_of("allowed_list").unwrap())?;
+        Ok(discover::diff(&allowed_list, &upstream))
+    });
+    match result {
+        Ok(diff) => {
+            for method in &diff.missing {
+                println!("- {}", method);
+            }
+            for method in &diff.unlisted {
+                println!("+ {}", method);
+            }
+            if diff.is_empty() {
+                0
+            } else {
+                1
+            }
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            2
+        }
+    }
+}
+
+/// Prints the mismatches and the summary of the replay, and returns 0 if every response matches, 1 if any differs or
+/// fails, or 2 if the record can't be read.
+async fn replay(args: &clap::ArgMatches<'_>) -> i32 {
+    let path = args.value_of("record").unwrap();
+    let forward = value_t_or_exit!(args, "forward", hyper::Uri);
+    let speed = value_t_or_exit!(args, "speed", f64);
+    if speed.is_nan() || speed < 0.0 {
+        clap::Error::value_validation_auto(format!("The speed {} is negative", speed)).exit();
+    }
+    let mask: Vec<_> = args.values_of("mask").map(|paths| paths.map(JsonPath::parse).collect()).unwrap_or_default();
+    let exchanges = match fs::File::open(path).map_err(Into::into).and_then(|file| record::load(BufReader::new(file))) {
+        Ok(exchanges) => exchanges,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            return 2
+        }
+    };
+    let mut summary = Summary::default();
+    record::replay(&forward, &exchanges, speed, &mask, |replayed| {
+        if let Some((recorded, response)) = &replayed.mismatch {
+            println!("{}:{}: {} differs: {} != {}", path, replayed.line, replayed.exchange.method, recorded, response);
+        }
+        summary.add(&replayed);
+    })
+    .await;
+    print!("{}", summary);
+    if summary.mismatches == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+/// Resolves on SIGTERM or SIGINT.
+#[cfg(unix)]
+async fn termination() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Cannot listen to SIGTERM");
+    let interrupt = Box::pin(tokio::signal::ctrl_c());
+    future::select(Box::pin(terminate.recv()), interrupt).await;
+}
+
+#[cfg(not(unix))]
+async fn termination() {
+    tokio::signal::ctrl_c().await.expect("Cannot listen to Ctrl-C");
 }
@@ -0,0 +1,118 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::bisect_set::BisectSet;
+use crate::perfect_hash::PerfectHashSet;
+use log::warn;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::iter::FromIterator;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// The methods of an allowed list.
+pub trait MethodSet: Send + Sync {
+    fn contains(&self, method: &str) -> bool;
+
+    /// Iterates the methods in sorted order.
+    fn methods(&self) -> Box<dyn Iterator<Item = &str> + '_>;
+}
+
+impl MethodSet for BisectSet<String> {
+    fn contains(&self, method: &str) -> bool {
+        BisectSet::contains(self, method)
+    }
+
+    fn methods(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        Box::new(self.iter().map(String::as_str))
+    }
+}
+
+impl MethodSet for PerfectHashSet {
+    fn contains(&self, method: &str) -> bool {
+        PerfectHashSet::contains(self, method)
+    }
+
+    fn methods(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        Box::new(self.iter().map(String::as_str))
+    }
+}
+
+/// How the methods of an allowed list are looked up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MethodSetKind {
+    /// A binary search in a sorted array.
+    Bisect,
+    /// A perfect hash, which is faster for large lists and for lists that miss often, but takes longer to build.
+    PerfectHash,
+}
+
+impl MethodSetKind {
+    /// Falls back to a binary search if the perfect hash can't be built.
+    pub fn build<I: IntoIterator<Item = String>>(self, methods: I) -> Arc<dyn MethodSet> {
+        match self {
+            MethodSetKind::Bisect => Arc::new(BisectSet::from_iter(methods)),
+            MethodSetKind::PerfectHash => {
+                let methods: Vec<_> = methods.into_iter().collect();
+                match PerfectHashSet::new(methods.iter().cloned()) {
+                    Some(set) => Arc::new(set),
+                    None => {
+                        warn!("Cannot build a perfect hash of {} methods, so they are bisected", methods.len());
+                        Arc::new(BisectSet::from_iter(methods))
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Display for MethodSetKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            MethodSetKind::Bisect => write!(f, "bisect"),
+            MethodSetKind::PerfectHash => write!(f, "perfect-hash"),
+        }
+    }
+}
+
+impl FromStr for MethodSetKind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind {
+            "bisect" => Ok(MethodSetKind::Bisect),
+            "perfect-hash" => Ok(MethodSetKind::PerfectHash),
+            _ => Err(format!("Unknown method set {}", kind)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_kind_has_the_same_methods() {
+        let methods = vec!["version".to_string(), "ping".to_string(), "chain_getSeq".to_string(), "ping".to_string()];
+        for kind in &[MethodSetKind::Bisect, MethodSetKind::PerfectHash] {
+            let set = kind.build(methods.clone());
+            assert!(set.contains("ping"), "{:?}", kind);
+            assert!(!set.contains("chain_getBalance"), "{:?}", kind);
+            assert_eq!(vec!["chain_getSeq", "ping", "version"], set.methods().collect::<Vec<_>>(), "{:?}", kind);
+        }
+        assert_eq!(Ok(MethodSetKind::PerfectHash), MethodSetKind::PerfectHash.to_string().parse());
+        assert!("fst".parse::<MethodSetKind>().is_err());
+    }
+}
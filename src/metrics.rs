@@ -0,0 +1,341 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::cache::Cache;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// The label of the methods that are not in the allowed list.
+const OTHER_METHOD: &str = "(other)";
+
+const LATENCY_BUCKETS: [u64; 11] =
+    [5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000, 2_500_000, 5_000_000, 10_000_000];
+const SIZE_BUCKETS: [u64; 6] = [100, 1_000, 10_000, 100_000, 1_000_000, 10_000_000];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Allowed,
+    Blocked,
+    Error,
+}
+
+const OUTCOMES: [Outcome; 3] = [Outcome::Allowed, Outcome::Blocked, Outcome::Error];
+
+impl Outcome {
+    pub fn label(self) -> &'static str {
+        match self {
+            Outcome::Allowed => "allowed",
+            Outcome::Blocked => "blocked",
+            Outcome::Error => "error",
+        }
+    }
+}
+
+struct Histogram {
+    bounds: &'static [u64],
+    /// The number of observations in each bucket, not cumulative. The last one is for `+Inf`.
+    counts: Vec<AtomicU64>,
+    sum: AtomicU64,
+    /// The number of observed units in an exported unit.
+    unit: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [u64], unit: f64) -> Self {
+        Histogram {
+            bounds,
+            counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            unit,
+        }
+    }
+
+    fn observe(&self, value: u64) {
+        let index = self.bounds.iter().position(|bound| value <= *bound).unwrap_or(self.bounds.len());
+        self.counts[index].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count.load(Ordering::Relaxed);
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, *bound as f64 / self.unit, cumulative).unwrap();
+        }
+        cumulative += self.counts[self.bounds.len()].load(Ordering::Relaxed);
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative).unwrap();
+        writeln!(out, "{}_sum {}", name, self.sum.load(Ordering::Relaxed) as f64 / self.unit).unwrap();
+        writeln!(out, "{}_count {}", name, cumulative).unwrap();
+    }
+}
+
+/// The counters of a method.
+#[derive(Default)]
+struct MethodCounters {
+    /// The requests by outcome.
+    requests: [AtomicU64; 3],
+    /// The requests that a rule in the monitor mode would block.
+    would_block: AtomicU64,
+}
+
+/// Counts the requests and the upstream calls.
+///
+/// Every counter is an atomic, so recording only takes a read lock to find the counters of the method. The methods
+/// are the ones of the allowed list, added with `add_methods` when it changes, and any other method is counted as
+/// `(other)`.
+pub struct Metrics {
+    /// Sorted by the method name.
+    methods: RwLock<Vec<(String, Arc<MethodCounters>)>>,
+    other: Arc<MethodCounters>,
+    in_flight_requests: AtomicUsize,
+    in_flight_upstream: AtomicUsize,
+    upstream_latency: Histogram,
+    request_bytes: Histogram,
+    response_bytes: Histogram,
+    coalesced: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new<'a, I: IntoIterator<Item = &'a str>>(methods: I) -> Self {
+        let metrics = Metrics {
+            methods: RwLock::new(Vec::new()),
+            other: Arc::default(),
+            in_flight_requests: AtomicUsize::new(0),
+            in_flight_upstream: AtomicUsize::new(0),
+            upstream_latency: Histogram::new(&LATENCY_BUCKETS, 1e6),
+            request_bytes: Histogram::new(&SIZE_BUCKETS, 1.0),
+            response_bytes: Histogram::new(&SIZE_BUCKETS, 1.0),
+            coalesced: AtomicU64::new(0),
+        };
+        metrics.add_methods(methods);
+        metrics
+    }
+
+    /// Counts the methods apart from `(other)` from now on, e.g. the ones added to the allowed list at runtime.
+    pub fn add_methods<'a, I: IntoIterator<Item = &'a str>>(&self, methods: I) {
+        let mut counters = self.methods.write().unwrap();
+        for method in methods {
+            if let Err(index) = counters.binary_search_by(|(name, _)| name.as_str().cmp(method)) {
+                counters.insert(index, (method.to_string(), Arc::default()));
+            }
+        }
+    }
+
+    /// `method` is `None` when the request doesn't have a valid method.
+    pub fn record_request(&self, method: Option<&str>, outcome: Outcome) {
+        let outcome = OUTCOMES.iter().position(|o| *o == outcome).expect("Every outcome is listed");
+        self.counters(method).requests[outcome].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn would_block(&self, method: Option<&str>) {
+        self.counters(method).would_block.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn counters(&self, method: Option<&str>) -> Arc<MethodCounters> {
+        let counters = self.methods.read().unwrap();
+        method
+            .and_then(|method| counters.binary_search_by(|(name, _)| name.as_str().cmp(method)).ok())
+            .map(|index| Arc::clone(&counters[index].1))
+            .unwrap_or_else(|| Arc::clone(&self.other))
+    }
+
+    pub fn request_started(&self) -> InFlight<'_> {
+        InFlight::new(&self.in_flight_requests)
+    }
+
+    pub fn in_flight_requests(&self) -> usize {
+        self.in_flight_requests.load(Ordering::Relaxed)
+    }
+
+    pub fn upstream_started(&self) -> InFlight<'_> {
+        InFlight::new(&self.in_flight_upstream)
+    }
+
+    pub fn observe_upstream_latency(&self, latency: Duration) {
+        self.upstream_latency.observe(latency.as_secs() * 1_000_000 + u64::from(latency.subsec_micros()));
+    }
+
+    pub fn observe_request_bytes(&self, bytes: usize) {
+        self.request_bytes.observe(bytes as u64);
+    }
+
+    pub fn observe_response_bytes(&self, bytes: usize) {
+        self.response_bytes.observe(bytes as u64);
+    }
+
+    pub fn coalesced(&self) {
+        self.coalesced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    pub fn render(&self, cache: &Cache) -> String {
+        let mut out = String::new();
+        let methods = self.methods.read().unwrap();
+        let counters = || {
+            methods
+                .iter()
+                .map(|(name, counters)| (name.as_str(), counters))
+                .chain(std::iter::once((OTHER_METHOD, &self.other)))
+        };
+        out.push_str("# HELP jsonrpc_filter_requests_total The number of JSON-RPC requests by method and outcome\n");
+        out.push_str("# TYPE jsonrpc_filter_requests_total counter\n");
+        for (name, counters) in counters() {
+            for (outcome, counter) in OUTCOMES.iter().zip(&counters.requests) {
+                let count = counter.load(Ordering::Relaxed);
+                if count != 0 {
+                    writeln!(
+                        out,
+                        "jsonrpc_filter_requests_total{{method=\"{}\",outcome=\"{}\"}} {}",
+                        name,
+                        outcome.label(),
+                        count
+                    )
+                    .unwrap();
+                }
+            }
+        }
+
+        out.push_str(
+            "# HELP jsonrpc_filter_would_block_total The number of JSON-RPC requests that a rule in the monitor mode \
+             would block\n",
+        );
+        out.push_str("# TYPE jsonrpc_filter_would_block_total counter\n");
+        for (name, counters) in counters() {
+            let count = counters.would_block.load(Ordering::Relaxed);
+            if count != 0 {
+                writeln!(out, "jsonrpc_filter_would_block_total{{method=\"{}\"}} {}", name, count).unwrap();
+            }
+        }
+
+        render_gauge(
+            &mut out,
+            "jsonrpc_filter_in_flight_requests",
+            "The number of requests being processed",
+            self.in_flight_requests.load(Ordering::Relaxed),
+        );
+        render_gauge(
+            &mut out,
+            "jsonrpc_filter_in_flight_upstream_requests",
+            "The number of requests waiting for the upstream",
+            self.in_flight_upstream.load(Ordering::Relaxed),
+        );
+        self.upstream_latency.render(
+            &mut out,
+            "jsonrpc_filter_upstream_latency_seconds",
+            "The time taken by the upstream to respond",
+        );
+        self.request_bytes.render(&mut out, "jsonrpc_filter_request_bytes", "The size of the request bodies");
+        self.response_bytes.render(&mut out, "jsonrpc_filter_response_bytes", "The size of the response bodies");
+        render_counter(
+            &mut out,
+            "jsonrpc_filter_cache_hits_total",
+            "The number of responses served from the cache",
+            cache.hits(),
+        );
+        render_counter(
+            &mut out,
+            "jsonrpc_filter_cache_misses_total",
+            "The number of cacheable requests sent to the upstream",
+            cache.misses(),
+        );
+        render_counter(
+            &mut out,
+            "jsonrpc_filter_coalesced_total",
+            "The number of requests answered by an identical request in flight",
+            self.coalesced.load(Ordering::Relaxed),
+        );
+        out
+    }
+}
+
+/// Renders the number of the entries dropped by each writer that fell behind, e.g. the access log.
+pub fn render_dropped_entries<'a, I: IntoIterator<Item = (&'a str, u64)>>(out: &mut String, writers: I) {
+    out.push_str(
+        "# HELP jsonrpc_filter_dropped_entries_total The number of log entries dropped by a writer that fell behind\n",
+    );
+    out.push_str("# TYPE jsonrpc_filter_dropped_entries_total counter\n");
+    for (writer, dropped) in writers {
+        writeln!(out, "jsonrpc_filter_dropped_entries_total{{writer=\"{}\"}} {}", writer, dropped).unwrap();
+    }
+}
+
+fn render_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value).unwrap();
+}
+
+fn render_gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value).unwrap();
+}
+
+/// Decreases the gauge when dropped.
+pub struct InFlight<'a>(&'a AtomicUsize);
+
+impl<'a> InFlight<'a> {
+    fn new(gauge: &'a AtomicUsize) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        InFlight(gauge)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::new(vec!["ping", "chain_getSeq"]);
+        metrics.record_request(Some("ping"), Outcome::Allowed);
+        metrics.record_request(Some("ping"), Outcome::Allowed);
+        metrics.record_request(Some("chain_sendSignedTransaction"), Outcome::Blocked);
+        metrics.record_request(None, Outcome::Error);
+        metrics.observe_upstream_latency(Duration::from_millis(20));
+        let _in_flight = metrics.request_started();
+
+        let rendered = metrics.render(&Cache::new(1));
+        assert!(rendered.contains("jsonrpc_filter_requests_total{method=\"ping\",outcome=\"allowed\"} 2\n"));
+        assert!(rendered.contains("jsonrpc_filter_requests_total{method=\"(other)\",outcome=\"blocked\"} 1\n"));
+        assert!(rendered.contains("jsonrpc_filter_requests_total{method=\"(other)\",outcome=\"error\"} 1\n"));
+        assert!(!rendered.contains("method=\"chain_getSeq\""));
+        assert!(rendered.contains("jsonrpc_filter_in_flight_requests 1\n"));
+        assert!(rendered.contains("jsonrpc_filter_upstream_latency_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(rendered.contains("jsonrpc_filter_upstream_latency_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(rendered.contains("jsonrpc_filter_upstream_latency_seconds_count 1\n"));
+    }
+
+    #[test]
+    fn count_the_added_methods() {
+        let metrics = Metrics::new(vec!["ping"]);
+        metrics.record_request(Some("ping"), Outcome::Allowed);
+        metrics.record_request(Some("version"), Outcome::Blocked);
+        metrics.add_methods(vec!["version", "ping"]);
+        metrics.record_request(Some("version"), Outcome::Allowed);
+
+        let rendered = metrics.render(&Cache::new(1));
+        assert!(rendered.contains("jsonrpc_filter_requests_total{method=\"ping\",outcome=\"allowed\"} 1\n"));
+        assert!(rendered.contains("jsonrpc_filter_requests_total{method=\"version\",outcome=\"allowed\"} 1\n"));
+        assert!(rendered.contains("jsonrpc_filter_requests_total{method=\"(other)\",outcome=\"blocked\"} 1\n"));
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+/// The average number of items in a bucket.
+const LAMBDA: usize = 5;
+/// The seeds tried before giving up on a set.
+const MAX_SEEDS: u64 = 16;
+/// The range of each of the two displacements tried for a bucket.
+const MAX_DISPLACEMENT: u32 = 64;
+
+/// A set of strings with a perfect hash, built when the set is made.
+///
+/// It's a hash-and-displace table: the items are grouped into buckets by a hash, and each bucket gets the
+/// displacement that puts all of its items in free slots. A lookup hashes the target once and compares it with a
+/// single item, so a miss costs as much as a hit. There are 5 slots for 4 items, so that the last buckets find a free
+/// slot within a few displacements.
+#[derive(Clone)]
+pub struct PerfectHashSet {
+    seed: u64,
+    /// The displacements of the buckets.
+    displacements: Vec<(u32, u32)>,
+    /// The index in `items` of the item in each slot.
+    slots: Vec<u32>,
+    /// Sorted and deduplicated.
+    items: Vec<String>,
+}
+
+struct Hashes {
+    bucket: usize,
+    f1: u32,
+    f2: u32,
+}
+
+fn hash(seed: u64, item: &str) -> Hashes {
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u64(seed);
+    hasher.write(item.as_bytes());
+    let first = hasher.finish();
+    hasher.write_u8(0xff);
+    let second = hasher.finish();
+    Hashes {
+        bucket: (first >> 32) as usize,
+        f1: first as u32,
+        f2: second as u32,
+    }
+}
+
+fn slot(hashes: &Hashes, (d1, d2): (u32, u32), len: usize) -> usize {
+    (u64::from(hashes.f1).wrapping_add(u64::from(d1).wrapping_mul(u64::from(hashes.f2))).wrapping_add(u64::from(d2))
+        % len as u64) as usize
+}
+
+impl PerfectHashSet {
+    /// Returns `None` if no seed places every item, which is unlikely but bounds the time to build.
+    pub fn new<I: IntoIterator<Item = String>>(iter: I) -> Option<Self> {
+        let mut items: Vec<_> = iter.into_iter().collect();
+        items.sort_unstable();
+        items.dedup();
+        let set = (0..MAX_SEEDS).find_map(|seed| PerfectHashSet::try_build(seed, &items))?;
+        Some(PerfectHashSet {
+            items,
+            ..set
+        })
+    }
+
+    /// Returns `None` if a bucket doesn't fit in the free slots with any displacement.
+    fn try_build(seed: u64, items: &[String]) -> Option<Self> {
+        let slot_count = (items.len() + items.len() / 4).max(1);
+        let bucket_count = (items.len().max(1) - 1) / LAMBDA + 1;
+        let hashes: Vec<_> = items.iter().map(|item| hash(seed, item)).collect();
+        let mut buckets: Vec<Vec<usize>> = vec![Vec::new(); bucket_count];
+        for (index, hashes) in hashes.iter().enumerate() {
+            buckets[hashes.bucket % bucket_count].push(index);
+        }
+        let mut order: Vec<_> = (0..bucket_count).collect();
+        order.sort_by_key(|bucket| std::cmp::Reverse(buckets[*bucket].len()));
+
+        let mut displacements = vec![(0, 0); bucket_count];
+        let mut slots = vec![None; slot_count];
+        let mut candidate = Vec::new();
+        for bucket in order {
+            let members = &buckets[bucket];
+            if members.is_empty() {
+                break
+            }
+            let found = (0..MAX_DISPLACEMENT).flat_map(|d1| (0..MAX_DISPLACEMENT).map(move |d2| (d1, d2))).find(
+                |displacement| {
+                    candidate.clear();
+                    for member in members {
+                        let slot = slot(&hashes[*member], *displacement, slot_count);
+                        if slots[slot].is_some() || candidate.contains(&slot) {
+                            return false
+                        }
+                        candidate.push(slot);
+                    }
+                    true
+                },
+            )?;
+            displacements[bucket] = found;
+            for (member, slot) in members.iter().zip(&candidate) {
+                slots[*slot] = Some(*member as u32);
+            }
+        }
+        Some(PerfectHashSet {
+            seed,
+            displacements,
+            slots: slots.into_iter().map(|slot| slot.unwrap_or(0)).collect(),
+            items: Vec::new(),
+        })
+    }
+
+    pub fn contains(&self, target: &str) -> bool {
+        if self.items.is_empty() {
+            return false
+        }
+        let hashes = hash(self.seed, target);
+        let displacement = self.displacements[hashes.bucket % self.displacements.len()];
+        let index = self.slots[slot(&hashes, displacement, self.slots.len())];
+        self.items[index as usize] == target
+    }
+
+    /// Iterates the items in sorted order.
+    pub fn iter(&self) -> std::slice::Iter<'_, String> {
+        self.items.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contains_only_the_items() {
+        let methods: Vec<_> = (0..10_000).map(|index| format!("method_{}", index)).collect();
+        let set = PerfectHashSet::new(methods.iter().cloned()).unwrap();
+        assert_eq!(10_000, set.len());
+        assert!(methods.iter().all(|method| set.contains(method)));
+        assert!(!set.contains("method_10000"));
+        assert!(!set.contains(""));
+        let mut sorted = methods;
+        sorted.sort();
+        assert_eq!(sorted, set.iter().cloned().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn small_sets() {
+        let empty = PerfectHashSet::new(Vec::new()).unwrap();
+        assert!(!empty.contains("ping"));
+        assert!(empty.is_empty());
+
+        let set = PerfectHashSet::new(vec!["ping".to_string(), "ping".to_string()]).unwrap();
+        assert_eq!(1, set.len());
+        assert!(set.contains("ping"));
+        assert!(!set.contains("pong"));
+    }
+}
@@ -0,0 +1,387 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! WebAssembly plugins.
+//!
+//! A plugin is a module that imports nothing and exports:
+//!
+//! - `memory`
+//! - `alloc(len: i32) -> i32`, which returns a buffer of `len` bytes for the input
+//! - `filter_request(ptr: i32, len: i32) -> i64`, optional
+//! - `filter_response(ptr: i32, len: i32) -> i64`, optional
+//!
+//! The filters are called with a UTF-8 JSON object of `method`, `request`, `peer`, `identity` and `tip`, and also
+//! `response` for `filter_response`. They return 0 to allow, or `ptr << 32 | len` of a UTF-8 JSON verdict, which is
+//! `{"deny": reason}` or `{"rewrite": message}`. Every call runs in a new instance, and a call that traps, runs out
+//! of fuel or time, or returns an invalid verdict denies the message.
+
+use super::Error;
+use crate::policy::{Decision, Policy, RequestInfo, ResponseFilter};
+use log::warn;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+use wasmtime::{Config, Engine, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+/// The interval of the epochs, which is the resolution of the timeout.
+const EPOCH_TICK: Duration = Duration::from_millis(1);
+
+const FILTER_REQUEST: &str = "filter_request";
+const FILTER_RESPONSE: &str = "filter_response";
+
+/// How much a plugin can do for a message. A plugin that exceeds them denies the message.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub fuel: u64,
+    pub timeout: Duration,
+    /// The maximum size of the linear memory in bytes.
+    pub max_memory: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            fuel: 10_000_000,
+            timeout: Duration::from_millis(50),
+            max_memory: 16 << 20,
+        }
+    }
+}
+
+struct Loaded {
+    instance: InstancePre<StoreLimits>,
+    filters_requests: bool,
+    filters_responses: bool,
+}
+
+/// Decides with a WebAssembly module, as a policy and as a response filter.
+pub struct WasmPlugin {
+    path: PathBuf,
+    engine: Engine,
+    limits: Limits,
+    loaded: RwLock<Arc<Loaded>>,
+    /// The number of the running calls. The thread that advances the epochs parks while it's 0.
+    running: Arc<AtomicUsize>,
+    ticker: thread::Thread,
+    /// Stops the thread that advances the epochs.
+    stopped: Arc<AtomicBool>,
+}
+
+impl WasmPlugin {
+    /// Loads a module in the binary or the text format.
+    pub fn load(path: PathBuf, limits: Limits) -> Result<Self, Error> {
+        let mut config = Config::new();
+        config.consume_fuel(true).epoch_interruption(true);
+        let engine = Engine::new(&config).map_err(|err| Error::Plugin(err.to_string()))?;
+        let loaded = load(&engine, &path)?;
+
+        let running = Arc::new(AtomicUsize::new(0));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let ticker_engine = engine.clone();
+        let ticker_running = Arc::clone(&running);
+        let ticker_stopped = Arc::clone(&stopped);
+        let ticker = thread::Builder::new().name("plugin-epoch".to_string()).spawn(move || {
+            while !ticker_stopped.load(Ordering::SeqCst) {
+                if ticker_running.load(Ordering::SeqCst) == 0 {
+                    thread::park();
+                    continue
+                }
+                thread::sleep(EPOCH_TICK);
+                ticker_engine.increment_epoch();
+            }
+        })?;
+        Ok(WasmPlugin {
+            path,
+            engine,
+            limits,
+            loaded: RwLock::new(Arc::new(loaded)),
+            running,
+            ticker: ticker.thread().clone(),
+            stopped,
+        })
+    }
+
+    /// Whether the plugin exports `filter_response`.
+    pub fn filters_responses(&self) -> bool {
+        self.loaded.read().unwrap().filters_responses
+    }
+
+    fn call(&self, loaded: &Loaded, export: &str, input: &Value) -> Result<Decision, Error> {
+        let plugin_error = |err: wasmtime::Error| Error::Plugin(format!("{}: {}", export, err));
+        let limits = StoreLimitsBuilder::new().memory_size(self.limits.max_memory).instances(1).build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.add_fuel(self.limits.fuel).map_err(plugin_error)?;
+        let ticks = self.limits.timeout.as_millis() / EPOCH_TICK.as_millis() + 1;
+        store.set_epoch_deadline(ticks as u64);
+
+        let instance = loaded.instance.instantiate(&mut store).map_err(plugin_error)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| Error::Plugin("The plugin doesn't export the memory".to_string()))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc").map_err(plugin_error)?;
+        let filter = instance.get_typed_func::<(i32, i32), i64>(&mut store, export).map_err(plugin_error)?;
+
+        let input = input.to_string();
+        let ptr = alloc.call(&mut store, input.len() as i32).map_err(plugin_error)?;
+        memory.write(&mut store, ptr as u32 as usize, input.as_bytes()).map_err(|err| plugin_error(err.into()))?;
+        let verdict = filter.call(&mut store, (ptr, input.len() as i32)).map_err(plugin_error)?;
+        if verdict == 0 {
+            return Ok(Decision::Allow)
+        }
+
+        let (ptr, len) = ((verdict >> 32) as u32 as usize, verdict as u32 as usize);
+        if len > self.limits.max_memory {
+            return Err(Error::Plugin(format!("{}: The verdict is too long", export)))
+        }
+        let mut output = vec![0; len];
+        memory.read(&store, ptr, &mut output).map_err(|err| plugin_error(err.into()))?;
+        parse_verdict(&output)
+    }
+
+    fn decide(&self, export: &str, input: &Value) -> Decision {
+        let loaded = Arc::clone(&self.loaded.read().unwrap());
+        let filters = if export == FILTER_REQUEST {
+            loaded.filters_requests
+        } else {
+            loaded.filters_responses
+        };
+        if !filters {
+            return Decision::Allow
+        }
+        if self.running.fetch_add(1, Ordering::SeqCst) == 0 {
+            self.ticker.unpark();
+        }
+        let result = self.call(&loaded, export, input);
+        self.running.fetch_sub(1, Ordering::SeqCst);
+        match result {
+            Ok(decision) => decision,
+            Err(err) => {
+                warn!("The plugin failed for {}: {}", input["method"], err);
+                Decision::Deny(format!("The plugin failed: {}", err))
+            }
+        }
+    }
+}
+
+impl Drop for WasmPlugin {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.ticker.unpark();
+    }
+}
+
+impl Policy for WasmPlugin {
+    fn check(&self, request: &RequestInfo<'_>) -> Decision {
+        self.decide(FILTER_REQUEST, &input(request))
+    }
+
+    fn reload(&self) -> Result<(), Error> {
+        let loaded = load(&self.engine, &self.path)?;
+        *self.loaded.write().unwrap() = Arc::new(loaded);
+        Ok(())
+    }
+
+    /// The plugins can read `tip`.
+    fn depends_on_tip(&self) -> bool {
+        true
+    }
+}
+
+impl ResponseFilter for WasmPlugin {
+    fn filter(&self, request: &RequestInfo<'_>, response: &Value) -> Decision {
+        let mut input = input(request);
+        input["response"] = response.clone();
+        self.decide(FILTER_RESPONSE, &input)
+    }
+}
+
+fn load(engine: &Engine, path: &Path) -> Result<Loaded, Error> {
+    let plugin_error = |err: wasmtime::Error| Error::Plugin(format!("{}: {}", path.display(), err));
+    let module = Module::from_file(engine, path).map_err(plugin_error)?;
+    let exports = |name: &str| module.exports().any(|export| export.name() == name);
+    let filters_requests = exports(FILTER_REQUEST);
+    let filters_responses = exports(FILTER_RESPONSE);
+    // Nothing is linked, so a module that imports anything is rejected here.
+    let instance = Linker::new(engine).instantiate_pre(&module).map_err(plugin_error)?;
+    Ok(Loaded {
+        instance,
+        filters_requests,
+        filters_responses,
+    })
+}
+
+fn input(request: &RequestInfo<'_>) -> Value {
+    json!({
+        "method": request.method,
+        "request": request.request,
+        "peer": request.peer.to_string(),
+        "identity": request.identity,
+        "tip": request.tip,
+    })
+}
+
+fn parse_verdict(output: &[u8]) -> Result<Decision, Error> {
+    let verdict = serde_json::from_slice::<Value>(output)?;
+    if let Some(reason) = verdict.get("deny") {
+        let reason = match reason {
+            Value::String(reason) => reason.clone(),
+            reason => reason.to_string(),
+        };
+        return Ok(Decision::Deny(reason))
+    }
+    if let Some(message) = verdict.get("rewrite") {
+        return Ok(Decision::Rewrite(message.clone()))
+    }
+    Err(Error::Plugin(format!("Invalid verdict: {}", verdict)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::HeaderMap;
+    use std::fs;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Instant;
+
+    /// Rewrites every request to the input of the plugin, and every response to a fixed one.
+    const PLUGIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (data (i32.const 64) "{\"rewrite\":{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":\"filtered\"}}")
+          (data (i32.const 1024) "{\"rewrite\":")
+          ;; Every call runs in a new instance, so the input always follows {"rewrite":
+          (func (export "alloc") (param $len i32) (result i32)
+            (i32.const 1035))
+          (func (export "filter_request") (param $ptr i32) (param $len i32) (result i64)
+            (i32.store8 (i32.add (local.get $ptr) (local.get $len)) (i32.const 125))
+            (i64.or
+              (i64.shl (i64.const 1024) (i64.const 32))
+              (i64.extend_i32_u (i32.add (local.get $len) (i32.const 12)))))
+          (func (export "filter_response") (param $ptr i32) (param $len i32) (result i64)
+            (i64.or (i64.shl (i64.const 64) (i64.const 32)) (i64.const 56))))
+    "#;
+
+    const DENY: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (data (i32.const 0) "{\"deny\":\"No transactions\"}")
+          (func (export "alloc") (param $len i32) (result i32)
+            (i32.const 1024))
+          (func (export "filter_request") (param $ptr i32) (param $len i32) (result i64)
+            (i64.const 26)))
+    "#;
+
+    const LOOP: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param $len i32) (result i32)
+            (i32.const 1024))
+          (func (export "filter_request") (param $ptr i32) (param $len i32) (result i64)
+            (loop $forever (br $forever))
+            (i64.const 0)))
+    "#;
+
+    fn load_plugin(name: &str, plugin: &str, limits: Limits) -> WasmPlugin {
+        let path = std::env::temp_dir().join(format!("jsonrpc-filter-{}-{}.wat", name, std::process::id()));
+        fs::write(&path, plugin).unwrap();
+        let plugin = WasmPlugin::load(path.clone(), limits).unwrap();
+        fs::remove_file(&path).unwrap();
+        plugin
+    }
+
+    fn info<'a>(request: &'a Value, headers: &'a HeaderMap) -> RequestInfo<'a> {
+        RequestInfo {
+            method: request["method"].as_str().unwrap(),
+            request,
+            peer: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            headers,
+            identity: None,
+            tip: Some(5000),
+        }
+    }
+
+    #[test]
+    fn decide_by_the_plugin() {
+        let plugin = load_plugin("decide", PLUGIN, Limits::default());
+        let headers = HeaderMap::new();
+        let ping = json!({"jsonrpc": "2.0", "id": 1, "method": "ping"});
+        assert_eq!(
+            Decision::Rewrite(json!({
+                "method": "ping",
+                "request": ping,
+                "peer": "127.0.0.1",
+                "identity": null,
+                "tip": 5000,
+            })),
+            plugin.check(&info(&ping, &headers))
+        );
+        assert!(plugin.filters_responses());
+        assert_eq!(
+            Decision::Rewrite(json!({"jsonrpc": "2.0", "id": 1, "result": "filtered"})),
+            plugin.filter(&info(&ping, &headers), &json!({"jsonrpc": "2.0", "id": 1, "result": "pong"}))
+        );
+
+        let plugin = load_plugin("deny", DENY, Limits::default());
+        let send = json!({"jsonrpc": "2.0", "id": 1, "method": "chain_sendSignedTransaction", "params": []});
+        assert_eq!(Decision::Deny("No transactions".to_string()), plugin.check(&info(&send, &headers)));
+        assert!(!plugin.filters_responses());
+    }
+
+    #[test]
+    fn allow_what_the_plugin_does_not_filter() {
+        let plugin = load_plugin("exports", r#"(module (memory (export "memory") 1))"#, Limits::default());
+        let headers = HeaderMap::new();
+        let ping = json!({"jsonrpc": "2.0", "id": 1, "method": "ping"});
+        assert_eq!(Decision::Allow, plugin.check(&info(&ping, &headers)));
+        assert_eq!(Decision::Allow, plugin.filter(&info(&ping, &headers), &json!({"result": "pong"})));
+    }
+
+    #[test]
+    fn stop_the_plugin_at_the_limits() {
+        let headers = HeaderMap::new();
+        let infinite_loop = json!({"jsonrpc": "2.0", "id": 1, "method": "loop"});
+        let plugin = load_plugin("fuel", LOOP, Limits::default());
+        match plugin.check(&info(&infinite_loop, &headers)) {
+            Decision::Deny(_) => {}
+            decision => panic!("The loop must be stopped: {:?}", decision),
+        }
+
+        let plugin = load_plugin("timeout", LOOP, Limits {
+            fuel: u64::MAX,
+            timeout: Duration::from_millis(10),
+            ..Limits::default()
+        });
+        let started = Instant::now();
+        match plugin.check(&info(&infinite_loop, &headers)) {
+            Decision::Deny(_) => {}
+            decision => panic!("The loop must be stopped: {:?}", decision),
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn reject_a_plugin_that_imports() {
+        let path = std::env::temp_dir().join(format!("jsonrpc-filter-imports-{}.wat", std::process::id()));
+        fs::write(&path, r#"(module (import "env" "now" (func)))"#).unwrap();
+        let result = WasmPlugin::load(path.clone(), Limits::default());
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
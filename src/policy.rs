@@ -0,0 +1,363 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::Error;
+use crate::allowed_list::{AllowedList, MethodOptions};
+use hyper::HeaderMap;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+/// What a policy knows about a request.
+pub struct RequestInfo<'a> {
+    pub method: &'a str,
+    /// The whole JSON-RPC request, including the method and the params.
+    pub request: &'a Value,
+    pub peer: IpAddr,
+    pub headers: &'a HeaderMap,
+    /// The client identity given by the authenticating proxy in front of the filter, if any.
+    pub identity: Option<&'a str>,
+    /// The best block number of the upstream, if it's watched.
+    pub tip: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Decision {
+    Allow,
+    Deny(String),
+    /// Forwards the given request instead.
+    Rewrite(Value),
+}
+
+/// Decides whether a request goes to the upstream.
+///
+/// The filter asks its policies in order. A request is forwarded only if no policy denies it, and the policies after
+/// a rewrite see the rewritten request.
+pub trait Policy: Send + Sync {
+    fn check(&self, request: &RequestInfo<'_>) -> Decision;
+
+    /// Reads the rules again. Called by `POST /policy/reload` of the admin server.
+    fn reload(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Whether the best block number of the upstream has to be watched.
+    fn depends_on_tip(&self) -> bool {
+        false
+    }
+}
+
+impl<F> Policy for F
+where
+    F: Fn(&RequestInfo<'_>) -> Decision + Send + Sync,
+{
+    fn check(&self, request: &RequestInfo<'_>) -> Decision {
+        self(request)
+    }
+}
+
+/// Decides whether a response goes to the client.
+///
+/// The filters are asked in order with the request that was forwarded. A denied response is replaced by an error,
+/// and the filters after a rewrite see the rewritten response. The cache keeps the responses of the upstream, so
+/// the cached responses are filtered again.
+pub trait ResponseFilter: Send + Sync {
+    fn filter(&self, request: &RequestInfo<'_>, response: &Value) -> Decision;
+}
+
+impl<F> ResponseFilter for F
+where
+    F: Fn(&RequestInfo<'_>, &Value) -> Decision + Send + Sync,
+{
+    fn filter(&self, request: &RequestInfo<'_>, response: &Value) -> Decision {
+        self(request, response)
+    }
+}
+
+/// The allowed list, the banned peers and the banned identities, which can be changed while the filter is running.
+pub struct AllowedListPolicy {
+    /// The file of the allowed list, read on reload and written on save.
+    path: Option<PathBuf>,
+    allowed_list: RwLock<Arc<AllowedList>>,
+    banned_peers: RwLock<HashSet<IpAddr>>,
+    /// The client identities, e.g. the API keys, given by the authenticating proxy in front of the filter.
+    banned_identities: RwLock<HashSet<String>>,
+}
+
+impl AllowedListPolicy {
+    pub fn load(path: PathBuf) -> Result<Self, Error> {
+        let mut policy = AllowedListPolicy::new(AllowedList::load(&path)?);
+        policy.path = Some(path);
+        Ok(policy)
+    }
+
+    /// A policy without a file, which can't be reloaded or saved.
+    pub fn new(allowed_list: AllowedList) -> Self {
+        AllowedListPolicy {
+            path: None,
+            allowed_list: RwLock::new(Arc::new(allowed_list)),
+            banned_peers: RwLock::new(HashSet::new()),
+            banned_identities: RwLock::new(HashSet::new()),
+        }
+    }
+
+    /// The current allowed list. A request keeps using the list it got even if the list changes meanwhile.
+    pub fn allowed_list(&self) -> Arc<AllowedList> {
+        Arc::clone(&self.allowed_list.read().unwrap())
+    }
+
+    /// Returns false if a `!` line denies the method, in which case the list is not changed.
+    pub fn allow(&self, method: &str, options: MethodOptions) -> bool {
+        let mut allowed_list = self.allowed_list.write().unwrap();
+        if allowed_list.is_denied(method) {
+            return false
+        }
+        *allowed_list = Arc::new(allowed_list.with_method(method, options));
+        true
+    }
+
+    /// Returns false if the method was not allowed.
+    pub fn deny(&self, method: &str) -> bool {
+        let mut allowed_list = self.allowed_list.write().unwrap();
+        if !allowed_list.contains(method) {
+            return false
+        }
+        *allowed_list = Arc::new(allowed_list.without_method(method));
+        true
+    }
+
+    /// Returns false if the peer was already banned.
+    pub fn ban(&self, peer: IpAddr) -> bool {
+        self.banned_peers.write().unwrap().insert(peer)
+    }
+
+    /// Returns false if the peer was not banned.
+    pub fn unban(&self, peer: IpAddr) -> bool {
+        self.banned_peers.write().unwrap().remove(&peer)
+    }
+
+    pub fn is_banned(&self, peer: IpAddr) -> bool {
+        self.banned_peers.read().unwrap().contains(&peer)
+    }
+
+    pub fn banned_peers(&self) -> BTreeSet<IpAddr> {
+        self.banned_peers.read().unwrap().iter().cloned().collect()
+    }
+
+    /// Returns false if the identity was already banned.
+    pub fn ban_identity(&self, identity: &str) -> bool {
+        self.banned_identities.write().unwrap().insert(identity.to_string())
+    }
+
+    /// Returns false if the identity was not banned.
+    pub fn unban_identity(&self, identity: &str) -> bool {
+        self.banned_identities.write().unwrap().remove(identity)
+    }
+
+    pub fn is_identity_banned(&self, identity: &str) -> bool {
+        self.banned_identities.read().unwrap().contains(identity)
+    }
+
+    pub fn banned_identities(&self) -> BTreeSet<String> {
+        self.banned_identities.read().unwrap().iter().cloned().collect()
+    }
+
+    /// Reads the allowed list from the file again. The current list is kept if the file is invalid.
+    pub fn reload(&self) -> Result<(), Error> {
+        let allowed_list = AllowedList::load(self.path()?)?;
+        *self.allowed_list.write().unwrap() = Arc::new(allowed_list);
+        Ok(())
+    }
+
+    /// Writes the current allowed list to the file, keeping its comments and the order of its lines.
+    pub fn save(&self) -> Result<(), Error> {
+        let path = self.path()?;
+        let contents = self.allowed_list().update(&fs::read_to_string(path)?);
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".tmp");
+        fs::write(&temporary, contents)?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    fn path(&self) -> Result<&PathBuf, Error> {
+        self.path.as_ref().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "The policy has no file").into())
+    }
+}
+
+impl Policy for AllowedListPolicy {
+    fn check(&self, request: &RequestInfo<'_>) -> Decision {
+        match self.allowed_list().denial(request.method) {
+            Some(denial) if !denial.monitored => Decision::Deny(denial.reason),
+            _ => Decision::Allow,
+        }
+    }
+
+    fn reload(&self) -> Result<(), Error> {
+        AllowedListPolicy::reload(self)
+    }
+
+    /// Responses cached until the next block have to be invalidated.
+    fn depends_on_tip(&self) -> bool {
+        self.allowed_list().depends_on_tip()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FilterBuilder;
+    use crate::filter::collect_body;
+    use crate::test_util::{filter_builder, policy, post, PEER};
+    use hyper::header::HeaderName;
+    use hyper::service::Service;
+    use hyper::{Body, Request};
+    use serde_json::json;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn change_save_and_reload() {
+        let path = std::env::temp_dir().join(format!("jsonrpc-filter-policy-{}.txt", std::process::id()));
+        fs::write(&path, "# Liveness\nping\nchain_getSeq cache=tip\n!personal_*\n").unwrap();
+        let policy = AllowedListPolicy::load(path.clone()).unwrap();
+
+        assert!(policy.allow("chain_getBalance", MethodOptions::parse("cache=10").unwrap()));
+        assert!(!policy.allow("personal_sign", MethodOptions::default()));
+        assert!(policy.deny("chain_getSeq"));
+        assert!(!policy.deny("chain_getSeq"));
+        policy.save().unwrap();
+        assert_eq!("# Liveness\nping\n!personal_*\nchain_getBalance cache=10\n", fs::read_to_string(&path).unwrap());
+
+        fs::write(&path, "ping\n").unwrap();
+        policy.reload().unwrap();
+        assert!(!policy.allowed_list().contains("chain_getBalance"));
+
+        fs::write(&path, "ping cache=forever\n").unwrap();
+        assert!(policy.reload().is_err());
+        assert!(policy.allowed_list().contains("ping"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ban_peers_and_identities() {
+        let policy = policy("ping");
+        let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        assert!(policy.ban(peer));
+        assert!(!policy.ban(peer));
+        assert!(policy.is_banned(peer));
+        assert!(!policy.is_banned(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert!(policy.unban(peer));
+        assert!(!policy.is_banned(peer));
+
+        assert!(policy.ban_identity("key1"));
+        assert!(!policy.ban_identity("key1"));
+        assert!(policy.is_identity_banned("key1"));
+        assert!(!policy.is_identity_banned("key2"));
+        assert!(policy.unban_identity("key1"));
+        assert!(!policy.unban_identity("key1"));
+        assert!(policy.reload().is_err());
+    }
+
+    #[tokio::test]
+    async fn ask_the_policies_in_order() {
+        let maker = FilterBuilder::new("http://127.0.0.1:1".parse().unwrap(), policy("ping"))
+            .identity_header(HeaderName::from_static("x-user"))
+            .policy(|request: &RequestInfo<'_>| match request.identity {
+                Some(_) => Decision::Allow,
+                None => Decision::Deny("anonymous".to_string()),
+            })
+            .build();
+        let mut filter = maker.filter(PEER);
+
+        let ping = r#"{"jsonrpc":"2.0","id":1,"method":"ping"}"#;
+        match filter.call(post(ping)).await {
+            Err(Error::Denied {
+                reason,
+                ..
+            }) => assert_eq!("anonymous", reason),
+            _ => panic!("An anonymous request must be denied"),
+        }
+        let request = Request::post("/").header("x-user", "alice").body(Body::from(ping)).unwrap();
+        match filter.call(request).await {
+            Err(Error::Hyper(_)) => {}
+            _ => panic!("The request must be forwarded to the unreachable upstream"),
+        }
+    }
+
+    #[tokio::test]
+    async fn check_the_rewritten_method() {
+        let maker = FilterBuilder::new("http://127.0.0.1:1".parse().unwrap(), policy("ping\nversion\n"))
+            .policy(|request: &RequestInfo<'_>| {
+                let mut rewrite = request.request.clone();
+                rewrite["method"] = match request.method {
+                    "ping" => json!("personal_sign"),
+                    "version" => json!("ping"),
+                    _ => return Decision::Allow,
+                };
+                Decision::Rewrite(rewrite)
+            })
+            .build();
+        let mut filter = maker.filter(PEER);
+
+        match filter.call(post(r#"{"jsonrpc":"2.0","id":1,"method":"ping"}"#)).await {
+            Err(Error::Denied {
+                method,
+                reason,
+            }) => assert_eq!(("personal_sign", "personal_sign is not in the allowed list"), (&method[..], &reason[..])),
+            _ => panic!("A rewrite to a method out of the allowed list must be denied"),
+        }
+        match filter.call(post(r#"{"jsonrpc":"2.0","id":1,"method":"version"}"#)).await {
+            Err(Error::Hyper(_)) => {}
+            _ => panic!("A rewrite to an allowed method must be forwarded to the unreachable upstream"),
+        }
+    }
+
+    #[tokio::test]
+    async fn filter_the_responses() {
+        let maker = filter_builder("ping", || Body::from(r#"{"jsonrpc":"2.0","id":1,"result":"pong"}"#))
+            .identity_header(HeaderName::from_static("x-user"))
+            .response_filter(|request: &RequestInfo<'_>, response: &Value| match request.identity {
+                Some(_) => {
+                    let mut response = response.clone();
+                    response["result"] = json!("PONG");
+                    Decision::Rewrite(response)
+                }
+                None => Decision::Deny("anonymous".to_string()),
+            })
+            .build();
+        let mut filter = maker.filter(PEER);
+
+        let ping = r#"{"jsonrpc":"2.0","id":1,"method":"ping"}"#;
+        match filter.call(post(ping)).await {
+            Err(Error::Denied {
+                reason,
+                ..
+            }) => assert_eq!("anonymous", reason),
+            _ => panic!("The response must be denied"),
+        }
+        let request = Request::post("/").header("x-user", "alice").body(Body::from(ping)).unwrap();
+        let response = filter.call(request).await.unwrap();
+        let body = collect_body(response.into_body()).await.unwrap();
+        assert_eq!(
+            json!({"jsonrpc": "2.0", "id": 1, "result": "PONG"}),
+            serde_json::from_slice::<Value>(&body).unwrap()
+        );
+    }
+}
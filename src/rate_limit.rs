@@ -0,0 +1,113 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Limits the rate of the requests of each peer.
+
+use lru::LruCache;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// The peers whose buckets are kept. Forgetting the least recent peer refills its bucket.
+const MAX_PEERS: usize = 10_000;
+
+/// A token bucket for each peer, which holds up to a second of requests and refills continuously.
+pub struct RateLimiter {
+    per_second: u32,
+    buckets: Mutex<LruCache<IpAddr, Bucket>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32) -> Self {
+        RateLimiter {
+            per_second,
+            buckets: Mutex::new(LruCache::new(MAX_PEERS)),
+        }
+    }
+
+    pub fn per_second(&self) -> u32 {
+        self.per_second
+    }
+
+    /// Takes a token of the peer, and returns false if it has none left.
+    pub fn acquire(&self, peer: IpAddr) -> bool {
+        self.acquire_at(peer, Instant::now())
+    }
+
+    fn acquire_at(&self, peer: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut bucket = buckets.peek(&peer).map(|bucket| self.refill(*bucket, now)).unwrap_or(Bucket {
+            tokens: f64::from(self.per_second),
+            updated: now,
+        });
+        let acquired = bucket.tokens >= 1.0;
+        if acquired {
+            bucket.tokens -= 1.0;
+        }
+        buckets.put(peer, bucket);
+        acquired
+    }
+
+    /// The whole tokens left to the peers that have used any.
+    pub fn remaining(&self) -> BTreeMap<IpAddr, u32> {
+        let now = Instant::now();
+        let buckets = self.buckets.lock().unwrap();
+        buckets
+            .iter()
+            .map(|(peer, bucket)| (*peer, self.refill(*bucket, now).tokens as u32))
+            .filter(|(_, tokens)| *tokens < self.per_second)
+            .collect()
+    }
+
+    fn refill(&self, bucket: Bucket, now: Instant) -> Bucket {
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        let capacity = f64::from(self.per_second);
+        Bucket {
+            tokens: (bucket.tokens + elapsed.as_secs_f64() * capacity).min(capacity),
+            updated: now.max(bucket.updated),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    #[test]
+    fn limit_each_peer() {
+        let limiter = RateLimiter::new(2);
+        let (alice, bob) = (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        let now = Instant::now();
+        assert!(limiter.acquire_at(alice, now));
+        assert!(limiter.acquire_at(alice, now));
+        assert!(!limiter.acquire_at(alice, now));
+        assert!(limiter.acquire_at(bob, now));
+
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.acquire_at(alice, later));
+        assert!(!limiter.acquire_at(alice, later));
+        assert_eq!(Some(&0), limiter.remaining().get(&alice));
+    }
+}
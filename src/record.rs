@@ -0,0 +1,386 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Records the allowed requests and their responses, and replays them against an upstream.
+
+use super::Error;
+use crate::access_log::{spawn_writer, Output, Queue};
+use crate::filter::collect_body;
+use crate::redact::JsonPath;
+use crate::shadow::{difference, response_value};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Client, Request, StatusCode};
+use serde_json::{json, Value};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{self, BufRead};
+use std::time::{Duration, Instant};
+
+/// The version of the format of the record, written in every line.
+pub const RECORD_VERSION: u64 = 1;
+
+/// A request and the response of the upstream, written as a line of the record.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exchange {
+    /// The time from the start of the recording to the request.
+    pub elapsed: Duration,
+    pub method: String,
+    /// The request sent to the upstream, after the rewrites of the policies.
+    pub request: Value,
+    pub status: u16,
+    /// The response of the upstream before the response rules and filters, as JSON, or as a string if it isn't JSON.
+    pub response: Value,
+    pub latency: Duration,
+}
+
+impl Exchange {
+    pub fn to_line(&self) -> String {
+        json!({
+            "version": RECORD_VERSION,
+            "elapsed_ms": self.elapsed.as_secs_f64() * 1000.0,
+            "method": self.method,
+            "request": self.request,
+            "status": self.status,
+            "response": self.response,
+            "latency_ms": self.latency.as_secs_f64() * 1000.0,
+        })
+        .to_string()
+    }
+
+    fn parse(line: &str) -> Result<Self, String> {
+        let exchange = serde_json::from_str::<Value>(line).map_err(|err| err.to_string())?;
+        let field = |name: &str| exchange.get(name).ok_or_else(|| format!("No {}", name));
+        let millis = |name: &str| {
+            field(name)?
+                .as_f64()
+                .filter(|millis| *millis >= 0.0)
+                .map(|millis| Duration::from_secs_f64(millis / 1000.0))
+                .ok_or_else(|| format!("Invalid {}", name))
+        };
+        match field("version")?.as_u64() {
+            Some(RECORD_VERSION) => {}
+            _ => return Err(format!("Unknown version {}", exchange["version"])),
+        }
+        Ok(Exchange {
+            elapsed: millis("elapsed_ms")?,
+            method: field("method")?.as_str().ok_or("Invalid method")?.to_string(),
+            request: field("request")?.clone(),
+            status: field("status")?.as_u64().filter(|status| *status <= 999).ok_or("Invalid status")? as u16,
+            response: field("response")?.clone(),
+            latency: millis("latency_ms")?,
+        })
+    }
+}
+
+/// Reads the exchanges of a record with their line numbers. Blank lines are skipped.
+pub fn load<R: BufRead>(reader: R) -> Result<Vec<(usize, Exchange)>, Error> {
+    let mut exchanges = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+        let exchange = Exchange::parse(&line).map_err(|reason| Error::InvalidRecord {
+            line: index + 1,
+            reason,
+        })?;
+        exchanges.push((index + 1, exchange));
+    }
+    Ok(exchanges)
+}
+
+/// Masks the values at the paths in the `params` of a request or the `result` of a response.
+fn mask(mut value: Value, member: &str, paths: &[JsonPath]) -> Value {
+    if let Some(member) = value.get_mut(member) {
+        for path in paths {
+            path.mask(member);
+        }
+    }
+    value
+}
+
+/// Writes the exchanges on a dedicated thread, like the access log.
+pub struct Recorder {
+    queue: Queue<String>,
+    started: Instant,
+    mask: Vec<JsonPath>,
+}
+
+impl Recorder {
+    /// The values at the `mask` paths in the params and the results are written as `***`.
+    pub fn new(output: Output, mask: Vec<JsonPath>) -> io::Result<Self> {
+        Ok(Recorder {
+            queue: spawn_writer("record", output)?,
+            started: Instant::now(),
+            mask,
+        })
+    }
+
+    pub fn record(&self, method: &str, request: &[u8], status: StatusCode, response: &[u8], latency: Duration) {
+        let exchange = Exchange {
+            elapsed: self.started.elapsed(),
+            method: method.to_string(),
+            request: mask(response_value(request), "params", &self.mask),
+            status: status.as_u16(),
+            response: mask(response_value(response), "result", &self.mask),
+            latency,
+        };
+        self.queue.send(exchange.to_line());
+    }
+
+    pub fn queue(&self) -> &Queue<String> {
+        &self.queue
+    }
+}
+
+/// An exchange sent again.
+pub struct Replayed<'a> {
+    pub line: usize,
+    pub exchange: &'a Exchange,
+    pub latency: Duration,
+    /// The recorded and the replayed responses if they differ, ignoring the `id` and the `jsonrpc` version. A failed
+    /// request differs with its error.
+    pub mismatch: Option<(String, String)>,
+}
+
+/// Sends the requests to the upstream one at a time, at `speed` times the recorded pace. A speed of 0 sends them as
+/// fast as the upstream answers.
+///
+/// The `mask_paths` of the record are masked in the replayed results too, so that they don't count as mismatches.
+pub async fn replay<F: FnMut(Replayed<'_>)>(
+    upstream: &hyper::Uri,
+    exchanges: &[(usize, Exchange)],
+    speed: f64,
+    mask_paths: &[JsonPath],
+    mut report: F,
+) {
+    let client = Client::new();
+    let started = Instant::now();
+    for (line, exchange) in exchanges {
+        if speed > 0.0 {
+            let at = started + exchange.elapsed.div_f64(speed);
+            tokio::time::delay_until(tokio::time::Instant::from_std(at)).await;
+        }
+        let request = Request::post(upstream.clone())
+            .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+            .body(Body::from(exchange.request.to_string()))
+            .expect("The request of a valid uri is valid");
+        let sent = Instant::now();
+        let response = async {
+            let response = client.request(request).await?;
+            let status = response.status().as_u16();
+            Ok::<_, Error>((status, collect_body(response.into_body()).await?))
+        }
+        .await;
+        let latency = sent.elapsed();
+        let recorded = || format!("{} {}", exchange.status, exchange.response);
+        let mismatch = match response {
+            Ok((status, buffer)) => {
+                let replayed = mask(response_value(&buffer), "result", mask_paths);
+                if status != exchange.status {
+                    Some((recorded(), format!("{} {}", status, replayed)))
+                } else {
+                    difference(exchange.response.clone(), replayed)
+                }
+            }
+            Err(err) => Some((recorded(), format!("Error: {}", err))),
+        };
+        report(Replayed {
+            line: *line,
+            exchange,
+            latency,
+            mismatch,
+        });
+    }
+}
+
+/// The number of mismatches and the latencies of a replay, compared with the recorded ones.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub requests: usize,
+    pub mismatches: usize,
+    latencies: Vec<Duration>,
+    recorded_latencies: Vec<Duration>,
+}
+
+impl Summary {
+    pub fn add(&mut self, replayed: &Replayed<'_>) {
+        self.requests += 1;
+        if replayed.mismatch.is_some() {
+            self.mismatches += 1;
+        }
+        self.latencies.push(replayed.latency);
+        self.recorded_latencies.push(replayed.exchange.latency);
+    }
+}
+
+/// The nearest-rank percentile in milliseconds.
+fn percentile(latencies: &[Duration], percentile: usize) -> f64 {
+    let mut latencies = latencies.to_vec();
+    latencies.sort();
+    let rank = (latencies.len() as f64 * percentile as f64 / 100.0).ceil() as usize;
+    latencies.get(rank.max(1) - 1).map_or(0.0, |latency| latency.as_secs_f64() * 1000.0)
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        writeln!(f, "{} requests, {} mismatches", self.requests, self.mismatches)?;
+        for (name, latencies) in &[("replayed", &self.latencies), ("recorded", &self.recorded_latencies)] {
+            writeln!(
+                f,
+                "{} latency: p50 {:.1}ms, p99 {:.1}ms, max {:.1}ms",
+                name,
+                percentile(latencies, 50),
+                percentile(latencies, 99),
+                percentile(latencies, 100)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FilterBuilder;
+    use crate::policy::{Decision, RequestInfo};
+    use crate::test_util::{policy, post, upstream, PEER};
+    use hyper::service::Service;
+
+    #[test]
+    fn read_the_record() {
+        let exchange = Exchange {
+            elapsed: Duration::from_millis(1500),
+            method: "ping".to_string(),
+            request: json!({"jsonrpc": "2.0", "id": 1, "method": "ping"}),
+            status: 200,
+            response: json!({"jsonrpc": "2.0", "id": 1, "result": "pong"}),
+            latency: Duration::from_millis(3),
+        };
+        let record = format!("{}\n\n{}\n", exchange.to_line(), exchange.to_line());
+        assert_eq!(vec![(1, exchange.clone()), (3, exchange)], load(record.as_bytes()).unwrap());
+
+        let unknown = r#"{"version":2,"elapsed_ms":0,"method":"ping","request":{},"status":200,"response":{}}"#;
+        match load(unknown.as_bytes()) {
+            Err(Error::InvalidRecord {
+                line,
+                reason,
+            }) => assert_eq!((1, "Unknown version 2"), (line, &reason[..])),
+            _ => panic!("The version must be checked"),
+        }
+        assert!(load(r#"{"version":1,"method":"ping"}"#.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn mask_the_params_and_the_results() {
+        let paths = vec![JsonPath::parse("1"), JsonPath::parse("signature")];
+        assert_eq!(
+            json!({"method": "personal_unlockAccount", "params": ["tccq9h7", "***"]}),
+            mask(json!({"method": "personal_unlockAccount", "params": ["tccq9h7", "passphrase"]}), "params", &paths)
+        );
+        assert_eq!(
+            json!({"result": {"seq": 1, "signature": "***"}}),
+            mask(json!({"result": {"seq": 1, "signature": "0x1"}}), "result", &paths)
+        );
+    }
+
+    #[test]
+    fn summarize_the_latencies() {
+        let exchange = load(
+            r#"{"version":1,"elapsed_ms":0,"method":"ping","request":{},"status":200,"response":{},"latency_ms":2}"#
+                .as_bytes(),
+        )
+        .unwrap()
+        .remove(0)
+        .1;
+        let mut summary = Summary::default();
+        for millis in 1..=10 {
+            summary.add(&Replayed {
+                line: millis as usize,
+                exchange: &exchange,
+                latency: Duration::from_millis(millis),
+                mismatch: if millis == 10 {
+                    Some(("{}".to_string(), "[]".to_string()))
+                } else {
+                    None
+                },
+            });
+        }
+        assert_eq!(
+            "10 requests, 1 mismatches\nreplayed latency: p50 5.0ms, p99 10.0ms, max 10.0ms\nrecorded latency: p50 \
+             2.0ms, p99 2.0ms, max 2.0ms\n",
+            summary.to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let forward = upstream(|| Body::from(r#"{"jsonrpc":"2.0","id":1,"result":"pong"}"#));
+        let path = std::env::temp_dir().join(format!("jsonrpc-filter-record-{}.jsonl", std::process::id()));
+        let output = Output::File {
+            path: path.clone(),
+            rotation: Default::default(),
+        };
+        let recorder = Recorder::new(output, vec![JsonPath::parse("0")]).unwrap();
+        let maker = FilterBuilder::new(forward.clone(), policy("ping"))
+            .recorder(recorder)
+            .response_filter(|_: &RequestInfo<'_>, response: &Value| {
+                let mut response = response.clone();
+                response["result"] = json!("PONG");
+                Decision::Rewrite(response)
+            })
+            .build();
+        let ping = r#"{"jsonrpc":"2.0","id":1,"method":"ping","params":["secret"]}"#;
+        let response = maker.filter(PEER).call(post(ping)).await.unwrap();
+        let body = collect_body(response.into_body()).await.unwrap();
+        assert_eq!(
+            json!({"jsonrpc": "2.0", "id": 1, "result": "PONG"}),
+            serde_json::from_slice::<Value>(&body).unwrap()
+        );
+
+        let mut exchanges = Vec::new();
+        for _ in 0..100 {
+            exchanges = load(std::fs::read(&path).unwrap().as_slice()).unwrap();
+            if !exchanges.is_empty() {
+                break
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(1, exchanges.len());
+        let (_, exchange) = &exchanges[0];
+        assert_eq!(json!({"jsonrpc": "2.0", "id": 1, "method": "ping", "params": ["***"]}), exchange.request);
+        assert_eq!(
+            (200, json!({"jsonrpc": "2.0", "id": 1, "result": "pong"})),
+            (exchange.status, exchange.response.clone())
+        );
+
+        let mut mismatches = Vec::new();
+        replay(&forward, &exchanges, 0.0, &[], |replayed| mismatches.push(replayed.mismatch)).await;
+        assert_eq!(vec![None], mismatches);
+        let changed = upstream(|| Body::from(r#"{"jsonrpc":"2.0","id":1,"result":"PONG"}"#));
+        mismatches.clear();
+        replay(&changed, &exchanges, 0.0, &[], |replayed| mismatches.push(replayed.mismatch)).await;
+        assert_eq!(vec![Some((r#"{"result":"pong"}"#.to_string(), r#"{"result":"PONG"}"#.to_string()))], mismatches);
+
+        // A request that fails is a mismatch, and the replay goes on.
+        let exchanges = vec![exchanges[0].clone(), exchanges[0].clone()];
+        mismatches.clear();
+        let unreachable = "http://127.0.0.1:1".parse().unwrap();
+        replay(&unreachable, &exchanges, 0.0, &[], |replayed| mismatches.push(replayed.mismatch)).await;
+        assert_eq!(2, mismatches.len());
+        assert!(mismatches.iter().all(|mismatch| mismatch.as_ref().unwrap().1.starts_with("Error: ")));
+    }
+}
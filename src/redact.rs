@@ -0,0 +1,253 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use serde_json::{json, Value};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// The value that replaces a masked field.
+const MASK: &str = "***";
+
+/// A path in the `result` of a response, e.g. `transactions.*.signature`.
+///
+/// A segment is an object key, an array index, or `*` for every item. The empty path is the `result` itself.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JsonPath {
+    segments: Vec<String>,
+}
+
+impl JsonPath {
+    pub fn parse(path: &str) -> Self {
+        JsonPath {
+            segments: if path.is_empty() {
+                Vec::new()
+            } else {
+                path.split('.').map(ToString::to_string).collect()
+            },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Replaces every value at the path with `***`.
+    pub fn mask(&self, value: &mut Value) {
+        self.for_each(value, &mut |value| *value = json!(MASK));
+    }
+
+    /// Calls `f` with every value at the path.
+    fn for_each<F: FnMut(&mut Value)>(&self, value: &mut Value, f: &mut F) {
+        visit(&self.segments, value, f)
+    }
+
+    /// Calls `f` with the parent of every value at the path and the last segment.
+    fn for_each_parent<F: FnMut(&mut Value, &str)>(&self, value: &mut Value, f: &mut F) {
+        if let Some((last, parents)) = self.segments.split_last() {
+            visit(parents, value, &mut |parent| f(parent, last));
+        }
+    }
+}
+
+fn visit<F: FnMut(&mut Value)>(segments: &[String], value: &mut Value, f: &mut F) {
+    let (segment, rest) = match segments.split_first() {
+        Some(split) => split,
+        None => return f(value),
+    };
+    match value {
+        Value::Array(items) if segment == "*" => {
+            for item in items {
+                visit(rest, item, f);
+            }
+        }
+        Value::Object(fields) if segment == "*" => {
+            for field in fields.values_mut() {
+                visit(rest, field, f);
+            }
+        }
+        Value::Array(items) => {
+            if let Some(item) = segment.parse::<usize>().ok().and_then(move |index| items.get_mut(index)) {
+                visit(rest, item, f);
+            }
+        }
+        Value::Object(fields) => {
+            if let Some(field) = fields.get_mut(segment.as_str()) {
+                visit(rest, field, f);
+            }
+        }
+        _ => {}
+    }
+}
+
+impl Display for JsonPath {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.segments.join("."))
+    }
+}
+
+/// Changes the responses of a method before they are sent to the client.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResponseRule {
+    /// Removes the fields or the array items at the path.
+    Remove(JsonPath),
+    /// Replaces the values at the path with `***`.
+    Mask(JsonPath),
+    /// Drops the items of the arrays at the path after the given number.
+    Limit(JsonPath, usize),
+    /// Replaces the message of an error with the generic one of its code, and removes its data.
+    GenericErrors,
+}
+
+impl ResponseRule {
+    fn apply(&self, response: &mut Value) {
+        match self {
+            ResponseRule::Remove(path) => {
+                if let Some(result) = response.get_mut("result") {
+                    path.for_each_parent(result, &mut |parent, last| match parent {
+                        Value::Object(fields) if last == "*" => fields.clear(),
+                        Value::Object(fields) => {
+                            fields.remove(last);
+                        }
+                        Value::Array(items) if last == "*" => items.clear(),
+                        Value::Array(items) => {
+                            if let Some(index) = last.parse::<usize>().ok().filter(|index| *index < items.len()) {
+                                items.remove(index);
+                            }
+                        }
+                        _ => {}
+                    });
+                }
+            }
+            ResponseRule::Mask(path) => {
+                if let Some(result) = response.get_mut("result") {
+                    path.mask(result);
+                }
+            }
+            ResponseRule::Limit(path, max_items) => {
+                if let Some(result) = response.get_mut("result") {
+                    path.for_each(result, &mut |value| {
+                        if let Value::Array(items) = value {
+                            items.truncate(*max_items);
+                        }
+                    });
+                }
+            }
+            ResponseRule::GenericErrors => {
+                if let Some(Value::Object(error)) = response.get_mut("error") {
+                    let message = generic_message(error.get("code").and_then(Value::as_i64));
+                    error.insert("message".to_string(), json!(message));
+                    error.remove("data");
+                }
+            }
+        }
+    }
+}
+
+/// Writes the rule as an option of the allowed list.
+impl Display for ResponseRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            ResponseRule::Remove(path) => write!(f, "remove={}", path),
+            ResponseRule::Mask(path) => write!(f, "mask={}", path),
+            ResponseRule::Limit(path, max_items) if path.is_empty() => write!(f, "limit={}", max_items),
+            ResponseRule::Limit(path, max_items) => write!(f, "limit={}:{}", path, max_items),
+            ResponseRule::GenericErrors => write!(f, "generic_errors"),
+        }
+    }
+}
+
+/// Applies the rules in order.
+pub fn apply(rules: &[ResponseRule], response: &mut Value) {
+    for rule in rules {
+        rule.apply(response);
+    }
+}
+
+/// The messages of the JSON-RPC 2.0 specification.
+fn generic_message(code: Option<i64>) -> &'static str {
+    match code {
+        Some(-32700) => "Parse error",
+        Some(-32600) => "Invalid Request",
+        Some(-32601) => "Method not found",
+        Some(-32602) => "Invalid params",
+        Some(-32603) => "Internal error",
+        _ => "Server error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allowed_list::MethodOptions;
+
+    fn redact(options: &str, mut response: Value) -> Value {
+        apply(&MethodOptions::parse(options).unwrap().response, &mut response);
+        response
+    }
+
+    #[test]
+    fn remove_and_mask_fields() {
+        let block = json!({"jsonrpc": "2.0", "id": 1, "result": {
+            "author": "tccq9h7vnl68frvqapzv3tujrxtxtwqdnxw6yamrrgd",
+            "transactions": [
+                {"seq": 1, "signature": "0x1", "tracker": "0xa"},
+                {"seq": 2, "signature": "0x2", "tracker": "0xb"},
+            ],
+        }});
+        assert_eq!(
+            json!({"jsonrpc": "2.0", "id": 1, "result": {
+                "author": "***",
+                "transactions": [{"seq": 1, "tracker": "0xa"}, {"seq": 2, "tracker": "0xb"}],
+            }}),
+            redact("remove=transactions.*.signature mask=author mask=unknown.path", block)
+        );
+    }
+
+    #[test]
+    fn remove_array_items() {
+        let block = json!({"jsonrpc": "2.0", "id": 1, "result": {"transactions": [1, 2, 3], "uncles": [[4, 5], [6]]}});
+        assert_eq!(
+            json!({"jsonrpc": "2.0", "id": 1, "result": {"transactions": [2, 3], "uncles": [[5], []]}}),
+            redact("remove=transactions.0 remove=uncles.*.0 remove=transactions.9", block)
+        );
+    }
+
+    #[test]
+    fn limit_arrays() {
+        let pending = json!({"jsonrpc": "2.0", "id": 1, "result": [1, 2, 3, 4]});
+        assert_eq!(json!({"jsonrpc": "2.0", "id": 1, "result": [1, 2]}), redact("limit=2", pending.clone()));
+        let nested = json!({"jsonrpc": "2.0", "id": 1, "result": {"transactions": [1, 2, 3, 4]}});
+        assert_eq!(
+            json!({"jsonrpc": "2.0", "id": 1, "result": {"transactions": [1, 2, 3]}}),
+            redact("limit=transactions:3", nested)
+        );
+        assert_eq!(pending.clone(), redact("limit=transactions:3", pending));
+    }
+
+    #[test]
+    fn replace_error_messages() {
+        let error = json!({"jsonrpc": "2.0", "id": 1, "error": {
+            "code": -32603,
+            "message": "Database at /var/lib/codechain is corrupted",
+            "data": "backtrace",
+        }});
+        assert_eq!(
+            json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32603, "message": "Internal error"}}),
+            redact("generic_errors", error)
+        );
+        let result = json!({"jsonrpc": "2.0", "id": 1, "result": "pong"});
+        assert_eq!(result.clone(), redact("generic_errors", result));
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::Error;
+use crate::policy::{Decision, Policy, RequestInfo};
+use log::warn;
+use rhai::serde::{from_dynamic, to_dynamic};
+use rhai::{Dynamic, Engine, EvalAltResult, ImmutableString, Scope, AST};
+use serde_json::Value;
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+thread_local! {
+    /// When the script running on this thread has to be stopped.
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// The operations between the checks of the deadline.
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+/// How much a script can do for a request. A script that exceeds them denies the request.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub max_operations: u64,
+    pub timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_operations: 100_000,
+            timeout: Duration::from_millis(10),
+        }
+    }
+}
+
+/// Decides with a Rhai script.
+///
+/// The script gets `method`, `params`, `request`, `peer`, `identity` and `tip`, which are `()` when unknown, and
+/// returns `allow()`, `deny(reason)`, `rewrite(request)` or a boolean.
+///
+/// The script runs on the thread of the request, which its limits keep short.
+pub struct ScriptPolicy {
+    path: PathBuf,
+    engine: Engine,
+    timeout: Duration,
+    ast: RwLock<Arc<AST>>,
+}
+
+impl ScriptPolicy {
+    pub fn load(path: PathBuf, limits: Limits) -> Result<Self, Error> {
+        let engine = engine(limits);
+        let ast = compile(&engine, &path)?;
+        Ok(ScriptPolicy {
+            path,
+            engine,
+            timeout: limits.timeout,
+            ast: RwLock::new(Arc::new(ast)),
+        })
+    }
+
+    fn run(&self, request: &RequestInfo<'_>) -> Result<Dynamic, Box<EvalAltResult>> {
+        let ast = Arc::clone(&self.ast.read().unwrap());
+        let mut scope = Scope::new();
+        scope.push_constant("method", request.method.to_string());
+        scope.push_constant_dynamic("params", to_dynamic(request.request.get("params").unwrap_or(&Value::Null))?);
+        scope.push_dynamic("request", to_dynamic(request.request)?);
+        scope.push_constant("peer", request.peer.to_string());
+        scope.push_constant_dynamic(
+            "identity",
+            request.identity.map(|identity| Dynamic::from(identity.to_string())).unwrap_or(Dynamic::UNIT),
+        );
+        scope.push_constant_dynamic("tip", request.tip.map(|tip| Dynamic::from(tip as i64)).unwrap_or(Dynamic::UNIT));
+
+        DEADLINE.with(|deadline| deadline.set(Some(Instant::now() + self.timeout)));
+        let result = self.engine.eval_ast_with_scope::<Dynamic>(&mut scope, &ast);
+        DEADLINE.with(|deadline| deadline.set(None));
+        result
+    }
+}
+
+impl Policy for ScriptPolicy {
+    fn check(&self, request: &RequestInfo<'_>) -> Decision {
+        match self.run(request) {
+            Ok(result) => {
+                if result.is::<Decision>() {
+                    result.cast::<Decision>()
+                } else {
+                    match result.as_bool() {
+                        Ok(true) => Decision::Allow,
+                        Ok(false) => Decision::Deny("Denied by the script".to_string()),
+                        Err(type_name) => Decision::Deny(format!("The script returned {}", type_name)),
+                    }
+                }
+            }
+            Err(err) => {
+                warn!("The script failed for {}: {}", request.method, err);
+                Decision::Deny(format!("The script failed: {}", err))
+            }
+        }
+    }
+
+    fn reload(&self) -> Result<(), Error> {
+        let ast = compile(&self.engine, &self.path)?;
+        *self.ast.write().unwrap() = Arc::new(ast);
+        Ok(())
+    }
+
+    /// The scripts can read `tip`.
+    fn depends_on_tip(&self) -> bool {
+        true
+    }
+}
+
+fn engine(limits: Limits) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(limits.max_operations)
+        .set_max_call_levels(32)
+        .set_max_string_size(1 << 20)
+        .set_max_array_size(10_000)
+        .set_max_map_size(10_000)
+        .on_progress(|operations| {
+            if operations % DEADLINE_CHECK_INTERVAL != 0 {
+                return None
+            }
+            DEADLINE.with(|deadline| match deadline.get() {
+                Some(deadline) if Instant::now() > deadline => Some(Dynamic::from("The script timed out")),
+                _ => None,
+            })
+        });
+    engine
+        .register_type_with_name::<Decision>("Decision")
+        .register_fn("allow", || Decision::Allow)
+        .register_fn("deny", |reason: ImmutableString| Decision::Deny(reason.to_string()))
+        .register_fn("rewrite", |request: Dynamic| from_dynamic::<Value>(&request).map(Decision::Rewrite));
+    engine
+}
+
+fn compile(engine: &Engine, path: &Path) -> Result<AST, Error> {
+    engine.compile_file(path.to_path_buf()).map_err(|err| Error::Script(format!("{}: {}", path.display(), err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::HeaderMap;
+    use serde_json::json;
+    use std::fs;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const SCRIPT: &str = r#"
+        if method == "chain_getBlockByNumber" && type_of(identity) == "()" && type_of(tip) != "()" && params[0] < tip - 1000 {
+            deny("Old blocks are only for the known users")
+        } else if method == "loop" {
+            loop {}
+        } else if method == "ping_v2" {
+            request.method = "ping";
+            rewrite(request)
+        } else {
+            true
+        }
+    "#;
+
+    fn check(policy: &ScriptPolicy, request: Value, identity: Option<&str>) -> Decision {
+        policy.check(&RequestInfo {
+            method: request["method"].as_str().unwrap(),
+            request: &request,
+            peer: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            headers: &HeaderMap::new(),
+            identity,
+            tip: Some(5000),
+        })
+    }
+
+    fn load(name: &str, script: &str, limits: Limits) -> ScriptPolicy {
+        let path = std::env::temp_dir().join(format!("jsonrpc-filter-{}-{}.rhai", name, std::process::id()));
+        fs::write(&path, script).unwrap();
+        let policy = ScriptPolicy::load(path.clone(), limits).unwrap();
+        fs::remove_file(&path).unwrap();
+        policy
+    }
+
+    #[test]
+    fn decide_by_the_script() {
+        let policy = load("decide", SCRIPT, Limits::default());
+        let old_block = json!({"jsonrpc": "2.0", "id": 1, "method": "chain_getBlockByNumber", "params": [10]});
+        assert_eq!(
+            Decision::Deny("Old blocks are only for the known users".to_string()),
+            check(&policy, old_block.clone(), None)
+        );
+        assert_eq!(Decision::Allow, check(&policy, old_block, Some("alice")));
+        let new_block = json!({"jsonrpc": "2.0", "id": 1, "method": "chain_getBlockByNumber", "params": [4500]});
+        assert_eq!(Decision::Allow, check(&policy, new_block, None));
+        assert_eq!(
+            Decision::Rewrite(json!({"jsonrpc": "2.0", "id": 1, "method": "ping"})),
+            check(&policy, json!({"jsonrpc": "2.0", "id": 1, "method": "ping_v2"}), None)
+        );
+    }
+
+    #[test]
+    fn stop_the_script_at_the_limits() {
+        let infinite_loop = json!({"jsonrpc": "2.0", "id": 1, "method": "loop"});
+        let policy = load("operations", SCRIPT, Limits::default());
+        match check(&policy, infinite_loop.clone(), None) {
+            Decision::Deny(_) => {}
+            decision => panic!("The loop must be stopped: {:?}", decision),
+        }
+
+        let policy = load("timeout", SCRIPT, Limits {
+            max_operations: 0,
+            timeout: Duration::from_millis(10),
+        });
+        let started = Instant::now();
+        match check(&policy, infinite_loop, None) {
+            Decision::Deny(reason) => assert!(reason.contains("terminated"), "{}", reason),
+            decision => panic!("The loop must be stopped: {:?}", decision),
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
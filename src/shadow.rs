@@ -0,0 +1,268 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Mirrors the allowed requests to a shadow upstream, e.g. a new version of the node under validation.
+
+use crate::allowed_list::denies;
+use crate::filter::collect_body_up_to;
+use futures::channel::oneshot;
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Client, Request};
+use log::{debug, warn};
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A shadow request that takes longer is abandoned.
+const SHADOW_TIMEOUT: Duration = Duration::from_secs(10);
+/// The maximum number of shadow requests in flight. A request is not mirrored while the shadow upstream is this far
+/// behind.
+const MAX_IN_FLIGHT: usize = 256;
+/// The methods that change the state of the node, which are not mirrored by default.
+pub const DEFAULT_EXCLUDE: [&str; 2] = ["mempool_send*", "personal_*"];
+
+/// Sends a copy of the requests to the shadow upstream in the background. The responses of the shadow upstream never
+/// reach the clients.
+pub struct Shadow {
+    upstream: hyper::Uri,
+    /// The percentage of the allowed requests to mirror.
+    percentage: f64,
+    compare: bool,
+    /// The patterns of the methods not to mirror, in the form of the `!` lines of the allowed list.
+    exclude: Vec<String>,
+    client: Client<HttpConnector>,
+    differences: Arc<AtomicU64>,
+    in_flight: Arc<AtomicUsize>,
+    skipped: AtomicU64,
+}
+
+impl Shadow {
+    pub fn new(upstream: hyper::Uri, percentage: f64) -> Self {
+        Shadow {
+            upstream,
+            percentage,
+            compare: false,
+            exclude: DEFAULT_EXCLUDE.iter().map(ToString::to_string).collect(),
+            client: Client::new(),
+            differences: Arc::new(AtomicU64::new(0)),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            skipped: AtomicU64::new(0),
+        }
+    }
+
+    /// Replaces the methods not to mirror, e.g. `personal_*`. They are `DEFAULT_EXCLUDE` unless given.
+    pub fn exclude<I: IntoIterator<Item = String>>(mut self, patterns: I) -> Self {
+        self.exclude = patterns.into_iter().collect();
+        self
+    }
+
+    /// Compares the responses of the shadow upstream with the ones of the upstream, and logs the differences.
+    pub fn compare(mut self, compare: bool) -> Self {
+        self.compare = compare;
+        self
+    }
+
+    /// The number of the compared responses that differed.
+    pub fn differences(&self) -> u64 {
+        self.differences.load(Ordering::Relaxed)
+    }
+
+    /// The number of the requests not mirrored because the shadow upstream was behind.
+    pub fn skipped(&self) -> u64 {
+        self.skipped.load(Ordering::Relaxed)
+    }
+
+    /// Decides whether to mirror a request of the method.
+    pub fn sample(&self, method: &str) -> bool {
+        if self.exclude.iter().any(|pattern| denies(pattern, method)) {
+            return false
+        }
+        self.percentage >= 100.0 || rand::random::<f64>() * 100.0 < self.percentage
+    }
+
+    /// Sends the request to the shadow upstream without waiting for it. If the responses are compared, the response of
+    /// the upstream has to be sent to the returned sender; dropping it skips the comparison.
+    /// The request is dropped if `MAX_IN_FLIGHT` shadow requests are in flight.
+    pub fn mirror(&self, seq: u64, method: &str, buffer: Vec<u8>, max_size: usize) -> Option<oneshot::Sender<Vec<u8>>> {
+        if self.in_flight.fetch_add(1, Ordering::Relaxed) >= MAX_IN_FLIGHT {
+            self.in_flight.fetch_sub(1, Ordering::Relaxed);
+            let skipped = self.skipped.fetch_add(1, Ordering::Relaxed) + 1;
+            // Logs at 1, 2, 4, 8, ... not to flood the log while the shadow upstream is behind.
+            if skipped.is_power_of_two() {
+                warn!("The shadow upstream is behind, and {} requests have not been mirrored", skipped);
+            }
+            return None
+        }
+        let in_flight = InFlight(Arc::clone(&self.in_flight));
+        let request = Request::post(self.upstream.clone())
+            .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+            .body(Body::from(buffer))
+            .expect("The request of a valid uri is valid");
+        let response = self.client.request(request);
+        let (primary, primary_received) = oneshot::channel::<Vec<u8>>();
+        let compare = self.compare;
+        let differences = Arc::clone(&self.differences);
+        let method = method.to_string();
+        tokio::spawn(async move {
+            let _in_flight = in_flight;
+            let shadow = async { collect_body_up_to(response.await?.into_body(), max_size).await };
+            let shadow = match tokio::time::timeout(SHADOW_TIMEOUT, shadow).await {
+                Ok(Ok(shadow)) => shadow,
+                Ok(Err(err)) => {
+                    warn!("seq: {}, the shadow upstream failed to respond to {}: {}", seq, method, err);
+                    return
+                }
+                Err(_) => {
+                    warn!("seq: {}, the shadow upstream didn't respond to {} in time", seq, method);
+                    return
+                }
+            };
+            if !compare {
+                return
+            }
+            let primary = match primary_received.await {
+                Ok(primary) => primary,
+                Err(_) => {
+                    debug!("seq: {}, no response of the upstream to compare", seq);
+                    return
+                }
+            };
+            if let Some((primary, shadow)) = difference(response_value(&primary), response_value(&shadow)) {
+                differences.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "seq: {}, the shadow upstream responded differently to {}: {} != {}",
+                    seq, method, primary, shadow
+                );
+            }
+        });
+        if compare {
+            Some(primary)
+        } else {
+            None
+        }
+    }
+}
+
+/// Decreases the number of the shadow requests in flight when dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Reads a response as JSON, or as a string if it isn't JSON.
+pub(crate) fn response_value(buffer: &[u8]) -> Value {
+    serde_json::from_slice(buffer).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(buffer).into_owned()))
+}
+
+/// Returns the different parts of the responses, ignoring the `id` and the `jsonrpc` version.
+pub(crate) fn difference(mut primary: Value, mut shadow: Value) -> Option<(String, String)> {
+    for response in &mut [&mut primary, &mut shadow] {
+        if let Value::Object(members) = response {
+            members.remove("id");
+            members.remove("jsonrpc");
+        }
+    }
+    if primary == shadow {
+        None
+    } else {
+        Some((primary.to_string(), shadow.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::collect_body;
+    use crate::test_util::{filter_builder, post, upstream, PEER};
+    use hyper::service::Service;
+    use serde_json::json;
+
+    #[test]
+    fn compare_the_results() {
+        let compare = |primary: &[u8], shadow: &[u8]| difference(response_value(primary), response_value(shadow));
+        let primary = br#"{"jsonrpc":"2.0","id":1,"result":{"seq":1}}"#;
+        assert_eq!(None, compare(primary, br#"{"id":1,"result":{"seq":1},"jsonrpc":"2.0"}"#));
+        assert_eq!(
+            Some((r#"{"result":{"seq":1}}"#.to_string(), r#"{"error":{"code":-32601}}"#.to_string())),
+            compare(primary, br#"{"jsonrpc":"2.0","id":1,"error":{"code":-32601}}"#)
+        );
+        assert_eq!(None, compare(b"Bad Gateway", b"Bad Gateway"));
+        assert_eq!(
+            Some((r#"{"result":{"seq":1}}"#.to_string(), r#""Bad Gateway""#.to_string())),
+            compare(primary, b"Bad Gateway")
+        );
+    }
+
+    #[test]
+    fn sample_the_percentage() {
+        let upstream: hyper::Uri = "http://127.0.0.1:1".parse().unwrap();
+        assert!((0..100).all(|_| Shadow::new(upstream.clone(), 100.0).sample("ping")));
+        assert!((0..100).all(|_| !Shadow::new(upstream.clone(), 0.0).sample("ping")));
+    }
+
+    #[test]
+    fn exclude_the_methods() {
+        let upstream: hyper::Uri = "http://127.0.0.1:1".parse().unwrap();
+        let shadow = Shadow::new(upstream.clone(), 100.0);
+        assert!(!shadow.sample("mempool_sendSignedTransaction"));
+        assert!(!shadow.sample("personal_sign"));
+        assert!(shadow.sample("chain_getBestBlockNumber"));
+        let shadow = Shadow::new(upstream, 100.0).exclude(vec!["chain_get*".to_string()]);
+        assert!(shadow.sample("personal_sign"));
+        assert!(!shadow.sample("chain_getBestBlockNumber"));
+    }
+
+    #[tokio::test]
+    async fn drop_the_requests_when_the_shadow_is_behind() {
+        // Nothing answers, so the shadow requests stay in flight until they time out.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = format!("http://{}", listener.local_addr().unwrap()).parse().unwrap();
+        let shadow = Shadow::new(upstream, 100.0);
+        for seq in 0..MAX_IN_FLIGHT as u64 + 2 {
+            shadow.mirror(seq, "ping", b"{}".to_vec(), 100);
+        }
+        assert_eq!(2, shadow.skipped());
+    }
+
+    #[tokio::test]
+    async fn mirror_to_the_shadow_upstream() {
+        let shadow = upstream(|| Body::from(r#"{"jsonrpc":"2.0","id":1,"result":"PONG"}"#));
+        let maker = filter_builder("ping", || Body::from(r#"{"jsonrpc":"2.0","id":1,"result":"pong"}"#))
+            .shadow(Shadow::new(shadow, 100.0).compare(true))
+            .build();
+        let ping = r#"{"jsonrpc":"2.0","id":1,"method":"ping"}"#;
+        let response = maker.filter(PEER).call(post(ping)).await.unwrap();
+        let body = collect_body(response.into_body()).await.unwrap();
+        assert_eq!(
+            json!({"jsonrpc": "2.0", "id": 1, "result": "pong"}),
+            serde_json::from_slice::<Value>(&body).unwrap()
+        );
+
+        let shadow = maker.config().shadow.as_ref().unwrap();
+        for _ in 0..100 {
+            if shadow.differences() != 0 {
+                break
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(1, shadow.differences());
+    }
+}
@@ -0,0 +1,50 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The helpers of the tests that send requests through a filter.
+
+use crate::allowed_list::AllowedList;
+use crate::config::FilterBuilder;
+use crate::policy::AllowedListPolicy;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, Uri};
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr};
+
+pub const PEER: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+pub fn post(body: &'static str) -> Request<Body> {
+    Request::post("/").body(Body::from(body)).unwrap()
+}
+
+pub fn policy(allowed_list: &str) -> AllowedListPolicy {
+    AllowedListPolicy::new(AllowedList::parse(allowed_list.as_bytes()).unwrap())
+}
+
+/// Spawns an upstream that responds with `body` to every request.
+pub fn upstream(body: fn() -> Body) -> Uri {
+    let upstream = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |_| async move { Ok::<_, Infallible>(Response::new(body())) }))
+    }));
+    let forward = format!("http://{}", upstream.local_addr()).parse().unwrap();
+    tokio::spawn(upstream);
+    forward
+}
+
+/// A filter of the allowed list in front of an upstream that responds with `body`.
+pub fn filter_builder(allowed_list: &str, body: fn() -> Body) -> FilterBuilder {
+    FilterBuilder::new(upstream(body), policy(allowed_list))
+}
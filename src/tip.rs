@@ -0,0 +1,59 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::Error;
+use crate::config::Config;
+use crate::filter::collect_body;
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Client, Request};
+use log::{debug, warn};
+use std::sync::Arc;
+use std::time::Duration;
+
+const BEST_BLOCK_NUMBER_REQUEST: &str = r#"{"jsonrpc":"2.0","id":0,"method":"chain_getBestBlockNumber","params":[]}"#;
+
+/// Polls the best block number of the upstream and moves the tip of the cache when it changes.
+///
+/// It doesn't poll while no policy depends on the tip, e.g. when no method of the allowed list caches its responses
+/// until the next block.
+pub async fn watch(config: Arc<Config>, interval: Duration) {
+    let client = Client::new();
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        if !config.policies.iter().any(|policy| policy.depends_on_tip()) {
+            continue
+        }
+        match best_block_number(&client, &config.forward).await {
+            Ok(number) => {
+                debug!("best block number: {}", number);
+                config.cache.set_tip(number);
+            }
+            Err(err) => warn!("Cannot get the best block number: {}", err),
+        }
+    }
+}
+
+async fn best_block_number(client: &Client<HttpConnector>, forward: &hyper::Uri) -> Result<u64, Error> {
+    let request = Request::post(forward.clone())
+        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+        .body(Body::from(BEST_BLOCK_NUMBER_REQUEST))?;
+    let response = client.request(request).await?;
+    let buffer = collect_body(response.into_body()).await?;
+    let response = serde_json::from_slice::<serde_json::Value>(&buffer)?;
+    response["result"].as_u64().ok_or_else(|| Error::UnexpectedResponse(response.to_string()))
+}
@@ -0,0 +1,346 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::access_log::{Queue, RotatingFile, Rotation};
+use crossbeam_channel::Receiver;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Client, Request};
+use log::warn;
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::runtime::{self, Runtime};
+
+/// The maximum number of traces sent to the collector at once.
+const MAX_BATCH: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+pub struct Span {
+    name: &'static str,
+    kind: SpanKind,
+    span_id: String,
+    parent_span_id: Option<String>,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+}
+
+/// A span that has been started but not finished.
+pub struct OpenSpan {
+    name: &'static str,
+    kind: SpanKind,
+    span_id: String,
+    start: SystemTime,
+}
+
+/// The spans of a request.
+///
+/// A request that comes with a W3C `traceparent` header continues the trace of the client.
+pub struct Trace {
+    trace_id: String,
+    root: Span,
+    spans: Vec<Span>,
+}
+
+impl Trace {
+    pub fn start(traceparent: Option<&HeaderValue>) -> Self {
+        let parent = traceparent.and_then(|value| value.to_str().ok()).and_then(parse_traceparent);
+        let (trace_id, parent_span_id) = match parent {
+            Some((trace_id, span_id)) => (trace_id, Some(span_id)),
+            None => (random_id(16), None),
+        };
+        let now = SystemTime::now();
+        Trace {
+            trace_id,
+            root: Span {
+                name: "request",
+                kind: SpanKind::Server,
+                span_id: random_id(8),
+                parent_span_id,
+                start: now,
+                end: now,
+                attributes: Vec::new(),
+            },
+            spans: Vec::new(),
+        }
+    }
+
+    pub fn open(&self, name: &'static str, kind: SpanKind) -> OpenSpan {
+        OpenSpan {
+            name,
+            kind,
+            span_id: random_id(8),
+            start: SystemTime::now(),
+        }
+    }
+
+    pub fn close(&mut self, span: OpenSpan, attributes: Vec<(&'static str, Value)>) {
+        self.spans.push(Span {
+            name: span.name,
+            kind: span.kind,
+            span_id: span.span_id,
+            parent_span_id: Some(self.root.span_id.clone()),
+            start: span.start,
+            end: SystemTime::now(),
+            attributes,
+        });
+    }
+
+    /// Adds an attribute to the span of the whole request.
+    pub fn set_attribute(&mut self, key: &'static str, value: Value) {
+        self.root.attributes.push((key, value));
+    }
+
+    /// The `traceparent` header that makes `span` the parent of the upstream.
+    pub fn traceparent(&self, span: &OpenSpan) -> String {
+        format!("00-{}-{}-01", self.trace_id, span.span_id)
+    }
+
+    fn finish(mut self) -> Self {
+        self.root.end = SystemTime::now();
+        self
+    }
+
+    fn to_otlp_spans(&self) -> impl Iterator<Item = Value> + '_ {
+        std::iter::once(&self.root).chain(&self.spans).map(move |span| {
+            let mut otlp = json!({
+                "traceId": self.trace_id,
+                "spanId": span.span_id,
+                "name": span.name,
+                "kind": span.kind as u8,
+                "startTimeUnixNano": unix_nanos(span.start),
+                "endTimeUnixNano": unix_nanos(span.end),
+                "attributes": span.attributes.iter().map(|(key, value)| json!({
+                    "key": key,
+                    "value": otlp_value(value),
+                })).collect::<Vec<_>>(),
+            });
+            if let Some(parent_span_id) = &span.parent_span_id {
+                otlp["parentSpanId"] = json!(parent_span_id);
+            }
+            otlp
+        })
+    }
+}
+
+/// Encodes the traces as an OTLP/JSON `ExportTraceServiceRequest`.
+fn to_otlp(traces: &[Trace]) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{"key": "service.name", "value": {"stringValue": "jsonrpc-filter"}}],
+            },
+            "scopeSpans": [{
+                "scope": {"name": "jsonrpc-filter", "version": env!("CARGO_PKG_VERSION")},
+                "spans": traces.iter().flat_map(Trace::to_otlp_spans).collect::<Vec<_>>(),
+            }],
+        }],
+    })
+}
+
+fn otlp_value(value: &Value) -> Value {
+    match value {
+        Value::Number(number) if number.is_i64() || number.is_u64() => json!({"intValue": number.to_string()}),
+        Value::Number(number) => json!({"doubleValue": number}),
+        Value::Bool(boolean) => json!({"boolValue": boolean}),
+        Value::String(string) => json!({"stringValue": string}),
+        other => json!({"stringValue": other.to_string()}),
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (u128::from(elapsed.as_secs()) * 1_000_000_000 + u128::from(elapsed.subsec_nanos())).to_string()
+}
+
+pub fn random_id(bytes: usize) -> String {
+    (0..bytes).map(|_| format!("{:02x}", rand::random::<u8>())).collect()
+}
+
+/// Returns the trace id and the parent span id.
+fn parse_traceparent(traceparent: &str) -> Option<(String, String)> {
+    let mut fields = traceparent.trim().split('-');
+    let version = fields.next()?;
+    let trace_id = fields.next()?;
+    let span_id = fields.next()?;
+    let _flags = fields.next()?;
+    let is_hex = |field: &str, len: usize| field.len() == len && field.bytes().all(|b| b.is_ascii_hexdigit());
+    let is_zero = |field: &str| field.bytes().all(|b| b == b'0');
+    if version != "00" || !is_hex(trace_id, 32) || !is_hex(span_id, 16) || is_zero(trace_id) || is_zero(span_id) {
+        return None
+    }
+    Some((trace_id.to_ascii_lowercase(), span_id.to_ascii_lowercase()))
+}
+
+pub enum Output {
+    /// Writes an OTLP/JSON request per line.
+    File {
+        path: PathBuf,
+        rotation: Rotation,
+    },
+    /// Sends the spans to an OTLP/HTTP collector, e.g. `http://127.0.0.1:4318/v1/traces`.
+    Collector(hyper::Uri),
+}
+
+/// Exports the finished traces in the background.
+pub struct Exporter {
+    queue: Queue<Trace>,
+}
+
+impl Exporter {
+    pub fn new(output: Output) -> io::Result<Self> {
+        let (queue, receiver) = Queue::new("trace-exporter");
+        match output {
+            Output::File {
+                path,
+                rotation,
+            } => {
+                let mut file = RotatingFile::open(path, rotation)?;
+                thread::Builder::new().name("trace-exporter".to_string()).spawn(move || {
+                    for trace in receiver {
+                        // A line is written at once, so that the rotation doesn't split it.
+                        let line = format!("{}\n", to_otlp(&[trace]));
+                        if let Err(err) = file.write_all(line.as_bytes()).and_then(|()| file.flush()) {
+                            warn!("Cannot write the traces: {}", err);
+                        }
+                    }
+                })?;
+            }
+            Output::Collector(uri) => {
+                // The collector has a runtime of its own, so that the exporter can be built outside of one.
+                let runtime = runtime::Builder::new().basic_scheduler().enable_all().build()?;
+                thread::Builder::new()
+                    .name("trace-exporter".to_string())
+                    .spawn(move || send_to_collector(runtime, uri, receiver))?;
+            }
+        }
+        Ok(Exporter {
+            queue,
+        })
+    }
+
+    pub fn export(&self, trace: Trace) {
+        self.queue.send(trace.finish());
+    }
+
+    pub fn queue(&self) -> &Queue<Trace> {
+        &self.queue
+    }
+}
+
+fn send_to_collector(mut runtime: Runtime, uri: hyper::Uri, receiver: Receiver<Trace>) {
+    let client = Client::new();
+    while let Ok(trace) = receiver.recv() {
+        let mut batch = vec![trace];
+        batch.extend(receiver.try_iter().take(MAX_BATCH - 1));
+        let request = Request::post(uri.clone())
+            .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+            .body(Body::from(to_otlp(&batch).to_string()))
+            .expect("The request is valid");
+        match runtime.block_on(client.request(request)) {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => warn!("The trace collector responded with {}", response.status()),
+            Err(err) => warn!("Cannot send the traces: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{FilterBuilder, ServiceMaker};
+    use crate::filter::collect_body;
+    use crate::test_util::{policy, PEER};
+    use hyper::service::{make_service_fn, service_fn, Service};
+    use hyper::{Response, Server};
+    use std::convert::Infallible;
+
+    #[test]
+    fn continue_the_trace_of_the_client() {
+        let traceparent = HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+        let mut trace = Trace::start(Some(&traceparent));
+        let span = trace.open("upstream", SpanKind::Client);
+        let upstream_traceparent = trace.traceparent(&span);
+        assert!(upstream_traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(upstream_traceparent.contains(&span.span_id));
+        trace.close(span, vec![("http.status_code", json!(200))]);
+
+        let otlp = to_otlp(&[trace.finish()]);
+        let spans = otlp["resourceSpans"][0]["scopeSpans"][0]["spans"].as_array().unwrap();
+        assert_eq!(2, spans.len());
+        assert_eq!(json!("00f067aa0ba902b7"), spans[0]["parentSpanId"]);
+        assert_eq!(spans[0]["spanId"], spans[1]["parentSpanId"]);
+        assert_eq!(json!({"intValue": "200"}), spans[1]["attributes"][0]["value"]);
+    }
+
+    #[test]
+    fn export_to_the_collector_outside_of_a_runtime() {
+        let exporter = Exporter::new(Output::Collector("http://127.0.0.1:1/v1/traces".parse().unwrap())).unwrap();
+        exporter.export(Trace::start(None));
+        assert_eq!(0, exporter.queue().dropped());
+    }
+
+    #[test]
+    fn ignore_invalid_traceparent() {
+        assert_eq!(None, parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01"));
+        assert_eq!(None, parse_traceparent("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"));
+        assert_eq!(None, parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736"));
+    }
+
+    #[tokio::test]
+    async fn pass_the_traceparent_through_unless_traced() {
+        let upstream = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                let traceparent = request.headers().get("traceparent").map(|value| value.to_str().unwrap().to_string());
+                let response = json!({"jsonrpc": "2.0", "id": 1, "result": traceparent});
+                Ok::<_, Infallible>(Response::new(Body::from(response.to_string())))
+            }))
+        }));
+        let forward: hyper::Uri = format!("http://{}", upstream.local_addr()).parse().unwrap();
+        tokio::spawn(upstream);
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let forwarded = |maker: ServiceMaker| async move {
+            let request = Request::post("/")
+                .header("traceparent", traceparent)
+                .body(Body::from(r#"{"jsonrpc":"2.0","id":1,"method":"ping"}"#))
+                .unwrap();
+            let response = maker.filter(PEER).call(request).await.unwrap();
+            let body = collect_body(response.into_body()).await.unwrap();
+            serde_json::from_slice::<Value>(&body).unwrap()["result"].as_str().unwrap().to_string()
+        };
+
+        assert_eq!(traceparent, forwarded(FilterBuilder::new(forward.clone(), policy("ping")).build()).await);
+
+        let path = std::env::temp_dir().join(format!("jsonrpc-filter-trace-{}.jsonl", std::process::id()));
+        let exporter = Exporter::new(Output::File {
+            path: path.clone(),
+            rotation: Default::default(),
+        })
+        .unwrap();
+        let traced = forwarded(FilterBuilder::new(forward, policy("ping")).trace_exporter(exporter).build()).await;
+        assert!(traced.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert_ne!(traceparent, traced);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
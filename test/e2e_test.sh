@@ -3,9 +3,18 @@ set -e
 
 SERVER_PORT=9991
 FILTER_PORT=9992
+ADMIN_PORT=9993
+ACCESS_LOG=$(mktemp)
+TRACE_FILE=$(mktemp)
+AUDIT_LOG=$(mktemp)
+RECORD=$(mktemp)
+ADMIN_TOKEN_FILE=$(mktemp)
+echo "e2e-token" > "$ADMIN_TOKEN_FILE"
 
 cargo build
 
+../target/debug/jsonrpc-filter check ./test_allowed.txt
+
 function tag {
   local TAG=$1
   while read LINE
@@ -21,12 +30,20 @@ RUST_LOG='jsonrpc_filter=trace' \
 ../target/debug/jsonrpc-filter \
   --allowed-list ./test_allowed.txt \
   --bind 127.0.0.1 --port $FILTER_PORT \
+  --admin-port $ADMIN_PORT \
+  --admin-token-file "$ADMIN_TOKEN_FILE" \
+  --audit-log "$AUDIT_LOG" \
+  --access-log "$ACCESS_LOG" \
+  --trace-file "$TRACE_FILE" \
+  --record "$RECORD" \
+  --identity-header x-api-key \
   --forward "http://127.0.0.1:$SERVER_PORT" 2>&1 | tag "[FILTER]" &
 
 function finish {
   set +e
   kill $(jobs -p)
   wait
+  rm -f "$ACCESS_LOG" "$TRACE_FILE" "$AUDIT_LOG" "$RECORD" "$ADMIN_TOKEN_FILE"
 }
 trap finish EXIT
 
@@ -70,6 +87,11 @@ function method_ping {
 }
 expect_success method_ping '{"jsonrpc":"2.0","result":"pong","id":1}'
 
+function method_cached_ping {
+  req '{"jsonrpc":"2.0","id":2,"method": "ping","params":[]}'
+}
+expect_success method_cached_ping '{"jsonrpc":"2.0","result":"pong","id":2}'
+
 function method_add {
   req '{"jsonrpc": "2.0","id":1,"method":"add","params":[1,2,3,4,5]}'
 }
@@ -85,3 +107,142 @@ function method_concat {
   req '{"jsonrpc":"2.0","id":1,"method":"concat","params":["hello, ","world!"]}'
 }
 expect_failure method_concat
+
+function expect_metric {
+  local METRIC=$1
+  echo "checking" "$METRIC"
+  if curl -s localhost:$ADMIN_PORT/metrics | grep -qF "$METRIC"
+  then
+    echo "success"
+  else
+    exit 255
+  fi
+}
+expect_metric 'jsonrpc_filter_requests_total{method="ping",outcome="allowed"} 2'
+expect_metric 'jsonrpc_filter_requests_total{method="(other)",outcome="blocked"} 1'
+expect_metric 'jsonrpc_filter_cache_hits_total 1'
+expect_metric 'jsonrpc_filter_dropped_entries_total{writer="access-log"} 0'
+expect_metric 'jsonrpc_filter_dropped_entries_total{writer="record"} 0'
+
+function expect_access_log {
+  local ENTRY=$1
+  echo "checking access log" "$ENTRY"
+  if grep -qF "$ENTRY" "$ACCESS_LOG"
+  then
+    echo "success"
+  else
+    exit 255
+  fi
+}
+expect_access_log '"decision":"allowed","identity":null,"latency_ms":'
+expect_access_log '"decision":"blocked","identity":null,"latency_ms":'
+expect_access_log '"method":"concat"'
+
+function expect_request_id {
+  echo "checking request id"
+  if curl -s -D - -o /dev/null -H "Content-Type: application/json" -H "X-Request-Id: e2e-request" \
+    -d '{"jsonrpc":"2.0","id":1,"method":"echo","params":[]}' localhost:$FILTER_PORT | grep -qi "^x-request-id: e2e-request"
+  then
+    echo "success"
+  else
+    exit 255
+  fi
+}
+expect_request_id
+expect_access_log '"request_id":"e2e-request"'
+
+function expect_trace {
+  local SPAN=$1
+  echo "checking trace" "$SPAN"
+  if grep -qF "$SPAN" "$TRACE_FILE"
+  then
+    echo "success"
+  else
+    exit 255
+  fi
+}
+expect_trace '"name":"upstream"'
+expect_trace '"stringValue":"e2e-request"'
+
+function admin {
+  local METHOD=$1
+  local URL_PATH=$2
+  local BODY=$3
+  curl -s -o /dev/null -w "%{http_code}" -X "$METHOD" -H "Authorization: Bearer e2e-token" -d "$BODY" \
+    localhost:$ADMIN_PORT$URL_PATH
+}
+
+function expect_status {
+  local EXPECTED=$1
+  local RESULT=$2
+  if [ "$RESULT" = "$EXPECTED" ]
+  then
+    echo "success"
+  else
+    echo "expected $EXPECTED, got $RESULT"
+    exit 255
+  fi
+}
+
+echo "checking the policy API without the token"
+expect_status 401 "$(curl -s -o /dev/null -w "%{http_code}" localhost:$ADMIN_PORT/policy)"
+
+echo "allowing concat"
+expect_status 200 "$(admin PUT /policy/methods/concat "coalesce")"
+function method_concat_allowed {
+  req '{"jsonrpc":"2.0","id":1,"method":"concat","params":["hello, ","world!"]}'
+}
+expect_success method_concat_allowed '{"jsonrpc":"2.0","result":"hello, world!","id":1}'
+
+echo "denying concat"
+expect_status 200 "$(admin DELETE /policy/methods/concat)"
+expect_failure method_concat
+expect_metric 'jsonrpc_filter_requests_total{method="concat",outcome="allowed"} 1'
+expect_metric 'jsonrpc_filter_requests_total{method="concat",outcome="blocked"} 1'
+
+echo "banning 127.0.0.1"
+expect_status 200 "$(admin PUT /policy/banned-peers/127.0.0.1)"
+expect_status 403 "$(curl -s -o /dev/null -w "%{http_code}" -d '{"jsonrpc":"2.0","id":1,"method":"ping"}' localhost:$FILTER_PORT)"
+expect_status 200 "$(admin DELETE /policy/banned-peers/127.0.0.1)"
+expect_success method_echo '{"jsonrpc":"2.0","result":["hello, ","world!"],"id":1}'
+
+echo "banning the api key key1"
+expect_status 200 "$(admin PUT /policy/banned-identities/key1)"
+expect_status 403 "$(curl -s -o /dev/null -w "%{http_code}" -H "X-Api-Key: key1" -d '{"jsonrpc":"2.0","id":1,"method":"ping"}' localhost:$FILTER_PORT)"
+expect_status 200 "$(curl -s -o /dev/null -w "%{http_code}" -H "X-Api-Key: key2" -d '{"jsonrpc":"2.0","id":1,"method":"ping"}' localhost:$FILTER_PORT)"
+expect_status 200 "$(admin DELETE /policy/banned-identities/key1)"
+
+echo "banning the api key key/1"
+expect_status 200 "$(admin PUT /policy/banned-identities/key%2F1)"
+expect_status 403 "$(curl -s -o /dev/null -w "%{http_code}" -H "X-Api-Key: key/1" -d '{"jsonrpc":"2.0","id":1,"method":"ping"}' localhost:$FILTER_PORT)"
+expect_status 200 "$(admin DELETE /policy/banned-identities/key%2F1)"
+
+function expect_audit_log {
+  local ENTRY=$1
+  echo "checking audit log" "$ENTRY"
+  if grep -qF "$ENTRY" "$AUDIT_LOG"
+  then
+    echo "success"
+  else
+    exit 255
+  fi
+}
+expect_audit_log '"method":"PUT","path":"/policy/methods/concat"'
+expect_audit_log '"status":401'
+
+echo "checking /healthz and /readyz"
+expect_status 200 "$(curl -s -o /dev/null -w "%{http_code}" localhost:$FILTER_PORT/healthz)"
+expect_status 200 "$(curl -s -o /dev/null -w "%{http_code}" localhost:$FILTER_PORT/readyz)"
+
+echo "comparing the allowed list with the methods of the upstream"
+DIFF=$(../target/debug/jsonrpc-filter diff-upstream --forward "http://127.0.0.1:$SERVER_PORT" ./test_allowed.txt || true)
+if [ "$DIFF" != "+ concat" ]
+then
+  echo "unexpected difference" "$DIFF"
+  exit 255
+fi
+echo "success"
+
+echo "replaying the recorded requests"
+../target/debug/jsonrpc-filter replay --speed 0 --forward "http://127.0.0.1:$SERVER_PORT" "$RECORD"
+echo "success"
@@ -12,6 +12,10 @@ handlers = {
     "concat": lambda params: "".join(params),
     "echo": lambda params: params,
 }
+handlers["rpc.discover"] = lambda _params: {
+    "openrpc": "1.2.6",
+    "methods": [{"name": name} for name in handlers if name != "rpc.discover"],
+}
 
 class TestServer(http.server.BaseHTTPRequestHandler):
     def do_POST(self):